@@ -15,9 +15,18 @@
 // Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
 // SPDX-License-Identifier: GPL-3.0-only
 
+use crate::{
+  config::{IconConfig, Preset, Style},
+  gauge::Gauge,
+};
+
 /// ASCII lettering from <http://www.patorjk.com/software/taag/#p=display&f=ANSI%20Regular&t=Type%20Something%20>
 
+/// How many lines of text every piece of ASCII art is, each of which is
+/// rasterized as two rows of pixels
 pub const HEIGHT: usize = 5;
+/// How many pixels are left empty around every side of an icon
+const PADDING: usize = 1;
 const ONE: &str = r#" ██ 
 ███ 
  ██ 
@@ -78,58 +87,346 @@ const ELLIPSIS: &str = r#"
          
          
 ██ ██ ██ "#;
-const SMILEY_FACE: &str = r#"   ██  
-██  ██ 
-    ██ 
-██  ██ 
-   ██  "#;
-
-/// Convert a number to ASCII art
-fn number_to_art(number: u64) -> String {
-  // Used for when an error occurs
-  if number == 1337 {
-    return QUESTION_MARK.to_string();
-  } else if number == 80085 {
-    // Used for when a background process is running
-    return ELLIPSIS.to_string();
-  } else if number == 43770 {
-    // The battery level display for the dummy process
-    return SMILEY_FACE.to_string();
+const CROSS: &str = r"██   ██ 
+ ██ ██  
+  ███   
+ ██ ██  
+██   ██ ";
+const BOLT: &str = r"   ▄█▀ 
+ ▄█▀   
+▀▀▀██▀ 
+ ▄█▀   
+█▀     ";
+/// A check mark, which stands in for "100" since three full-width digits are
+/// far too wide for a square icon
+const FULL: &str = r"      ██ 
+     ██  
+██  ██   
+ ████    
+  ██     ";
+
+/// Narrow digits, for when the regular ones wouldn't fit side by side
+const NARROW_ONE: &str = r" █  
+██  
+ █  
+ █  
+███ ";
+const NARROW_TWO: &str = r"███ 
+  █ 
+███ 
+█   
+███ ";
+const NARROW_THREE: &str = r"███ 
+  █ 
+███ 
+  █ 
+███ ";
+const NARROW_FOUR: &str = r"█ █ 
+█ █ 
+███ 
+  █ 
+  █ ";
+const NARROW_FIVE: &str = r"███ 
+█   
+███ 
+  █ 
+███ ";
+const NARROW_SIX: &str = r"███ 
+█   
+███ 
+█ █ 
+███ ";
+const NARROW_SEVEN: &str = r"███ 
+  █ 
+  █ 
+  █ 
+  █ ";
+const NARROW_EIGHT: &str = r"███ 
+█ █ 
+███ 
+█ █ 
+███ ";
+const NARROW_NINE: &str = r"███ 
+█ █ 
+███ 
+  █ 
+███ ";
+const NARROW_ZERO: &str = r"███ 
+█ █ 
+█ █ 
+█ █ 
+███ ";
+/// How many regular digits, counting the bolt as one, fit side by side before
+/// the narrow ones are used instead
+const REGULAR_DIGITS: usize = 2;
+
+/// Something to show in the tray icon
+///
+/// Every status the tray can be in has its own glyph, so new ones are added
+/// here along with their art in `Glyph::art`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Glyph {
+  /// A battery level, in percent
+  Percent(u8),
+  /// A battery level, in percent, of a device which is charging
+  Charging(u8),
+  /// An ellipsis, for when a background process is running
+  Loading,
+  /// A question mark, for when the battery level couldn't be fetched
+  Error,
+  /// A cross, for when the battery source can't be reached
+  Disconnected,
+}
+
+impl Glyph {
+  /// Convert the glyph to ASCII art
+  fn art(self) -> String {
+    match self {
+      Self::Percent(100) => FULL.to_string(),
+      Self::Charging(100) => join(&[BOLT, FULL]),
+      Self::Percent(percentage) => join(&digits(percentage, 0)),
+      Self::Charging(percentage) =>
+        join(&[&[BOLT], digits(percentage, 1).as_slice()].concat()),
+      Self::Loading => ELLIPSIS.to_string(),
+      Self::Error => QUESTION_MARK.to_string(),
+      Self::Disconnected => CROSS.to_string(),
+    }
+  }
+
+  /// Rasterize the glyph into a PNG, painted with `palette`
+  pub fn to_image(self, palette: &Palette) -> Vec<u8> {
+    paint(&pixels(&self.art(), palette.foreground(self)), palette)
+  }
+}
+
+/// Which colors glyphs are painted with
+pub struct Palette {
+  /// Foreground colors and the battery levels they're used above, highest
+  /// first
+  bands: Vec<(u8, [u8; 4])>,
+  /// The foreground color of glyphs which don't show a battery level
+  status: [u8; 4],
+  outline: Option<[u8; 4]>,
+  background: Option<[u8; 4]>,
+}
+
+impl Palette {
+  pub fn from_config(config: &IconConfig) -> Self {
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    // Gray is readable on both light and dark taskbars
+    const GRAY: [u8; 4] = [158, 158, 158, 255];
+
+    let (bands, status) = match config.preset {
+      Preset::White => (vec![(0, WHITE)], WHITE),
+      Preset::Black => (vec![(0, BLACK)], BLACK),
+      Preset::Traffic => (
+        vec![
+          (50, [76, 175, 80, 255]),
+          (20, [255, 193, 7, 255]),
+          (0, [244, 67, 54, 255]),
+        ],
+        GRAY,
+      ),
+      Preset::OkabeIto => (
+        vec![
+          (50, [0, 114, 178, 255]),
+          (20, [230, 159, 0, 255]),
+          (0, [213, 94, 0, 255]),
+        ],
+        GRAY,
+      ),
+      Preset::Ibm => (
+        vec![
+          (50, [100, 143, 255, 255]),
+          (20, [255, 176, 0, 255]),
+          (0, [220, 38, 127, 255]),
+        ],
+        GRAY,
+      ),
+    };
+    let mut bands = config.bands.as_ref().map_or(bands, |bands| {
+      bands
+        .iter()
+        .map(|band| (band.above, band.color.0))
+        .collect()
+    });
+
+    bands.sort_by_key(|&(above, _)| std::cmp::Reverse(above));
+
+    Self {
+      bands,
+      status: config.status.map_or(status, |color| color.0),
+      outline: config.outline.map(|color| color.0),
+      background: config.background.map(|color| color.0),
+    }
+  }
+
+  /// The color to paint a glyph with, picked by its battery level
+  ///
+  /// Battery levels at or below every band fall back to the lowest band.
+  pub fn foreground(&self, glyph: Glyph) -> [u8; 4] {
+    match glyph {
+      Glyph::Percent(percentage) | Glyph::Charging(percentage) => self
+        .bands
+        .iter()
+        .find(|(above, _)| percentage > *above)
+        .or_else(|| self.bands.last())
+        .map_or(self.status, |(_, color)| *color),
+      Glyph::Loading | Glyph::Error | Glyph::Disconnected => self.status,
+    }
+  }
+
+  /// The foreground color of glyphs which don't show a battery level
+  pub const fn status(&self) -> [u8; 4] { self.status }
+}
+
+/// Draws glyphs in whichever style the configuration asks for
+pub struct Renderer {
+  palette: Palette,
+  /// The battery gauge, if glyphs are drawn as one instead of as digits
+  gauge: Option<Gauge>,
+}
+
+impl Renderer {
+  pub fn from_config(config: &IconConfig) -> Self {
+    Self {
+      palette: Palette::from_config(config),
+      gauge: match config.style {
+        Style::Digits => None,
+        Style::Gauge => Some(Gauge::from_config(&config.gauge)),
+      },
+    }
+  }
+
+  /// Rasterize a glyph into a PNG
+  pub fn render(&self, glyph: Glyph) -> Vec<u8> {
+    self.gauge.as_ref().map_or_else(
+      || glyph.to_image(&self.palette),
+      |gauge| gauge.to_image(glyph, &self.palette),
+    )
   }
+}
+
+/// Rows of pixels, with `None` for pixels that aren't part of what's drawn
+pub type Pixels = Vec<Vec<Option<[u8; 4]>>>;
+
+/// Draw a piece of ASCII art in a single color
+///
+/// Every line of the ASCII art is two pixels tall, so that half-blocks can
+/// fill just the top or bottom pixel. The top halves of a line's characters
+/// make up its first row of pixels, and the bottom halves its second.
+fn pixels(art: &str, color: [u8; 4]) -> Pixels {
+  let fill = move |filled: bool| filled.then_some(color);
+
+  art
+    .lines()
+    .flat_map(|line| {
+      let line = line.chars().map(halves).collect::<Vec<_>>();
+
+      [
+        line.iter().map(|&(upper, _)| fill(upper)).collect(),
+        line.iter().map(|&(_, lower)| fill(lower)).collect(),
+      ]
+    })
+    .collect()
+}
 
-  let to_art_digit = |number: u64| match number {
-    0 => ZERO,
-    1 => ONE,
-    2 => TWO,
-    3 => THREE,
-    4 => FOUR,
-    5 => FIVE,
-    6 => SIX,
-    7 => SEVEN,
-    8 => EIGHT,
-    9 => NINE,
-    _ => unreachable!(),
+/// Paint pixels into a PNG, outlining them and filling in the background if
+/// the palette asks for it
+///
+/// Whatever's drawn is centered on a square canvas, with empty columns on
+/// either side trimmed off first so that every glyph gets the same padding.
+pub fn paint(pixels: &Pixels, palette: &Palette) -> Vec<u8> {
+  let columns = pixels.first().map_or(0, Vec::len);
+  let drawn = |x: usize| pixels.iter().any(|row| row[x].is_some());
+  let left = (0..columns).find(|&x| drawn(x)).unwrap_or(0);
+  let right = (0..columns).rev().find(|&x| drawn(x)).map_or(0, |x| x + 1);
+  let drawn_width = right.saturating_sub(left);
+  // Also leaves outlines a pixel of room around what's drawn
+  let size = drawn_width.max(pixels.len()) + PADDING * 2;
+  let (width, height) = (size, size);
+  let (x_offset, y_offset) =
+    ((size - drawn_width) / 2, (size - pixels.len()) / 2);
+  let pixel = |x: usize, y: usize| {
+    let x = x.checked_sub(x_offset).filter(|&x| x < drawn_width)?;
+
+    pixels
+      .get(y.checked_sub(y_offset)?)
+      .and_then(|row| row.get(x + left))
+      .copied()
+      .flatten()
   };
+  let mut image = Vec::with_capacity(width * height * 4);
+
+  for y in 0..height {
+    for x in 0..width {
+      let color = pixel(x, y).unwrap_or_else(|| {
+        let touching = (y.saturating_sub(1)..=y + 1).any(|y| {
+          (x.saturating_sub(1)..=x + 1).any(|x| pixel(x, y).is_some())
+        });
+
+        match palette.outline {
+          Some(outline) if touching => outline,
+          // Transparent, unless there's a background plate
+          _ => palette.background.unwrap_or_default(),
+        }
+      });
+
+      image.extend_from_slice(&color);
+    }
+  }
+
+  // Create an image from the pixel data
+  lodepng::encode_memory(&image, width, height, lodepng::ColorType::RGBA, 8)
+    .unwrap_or_else(|_| panic!("unable to encode {width}x{height} image"))
+}
+
+/// The ASCII art of every digit of a number, in the narrow font if the
+/// digits and `others` pieces of art next to them wouldn't fit otherwise
+fn digits(number: u8, others: usize) -> Vec<&'static str> {
+  let number = number.to_string();
+  let narrow = number.len() + others > REGULAR_DIGITS;
+
+  number
+    .chars()
+    .map(|digit| match (digit, narrow) {
+      ('0', false) => ZERO,
+      ('1', false) => ONE,
+      ('2', false) => TWO,
+      ('3', false) => THREE,
+      ('4', false) => FOUR,
+      ('5', false) => FIVE,
+      ('6', false) => SIX,
+      ('7', false) => SEVEN,
+      ('8', false) => EIGHT,
+      ('9', false) => NINE,
+      ('0', true) => NARROW_ZERO,
+      ('1', true) => NARROW_ONE,
+      ('2', true) => NARROW_TWO,
+      ('3', true) => NARROW_THREE,
+      ('4', true) => NARROW_FOUR,
+      ('5', true) => NARROW_FIVE,
+      ('6', true) => NARROW_SIX,
+      ('7', true) => NARROW_SEVEN,
+      ('8', true) => NARROW_EIGHT,
+      ('9', true) => NARROW_NINE,
+      _ => unreachable!(),
+    })
+    .collect()
+}
+
+/// Put pieces of ASCII art side by side
+fn join(pieces: &[&str]) -> String {
   let mut art = String::new();
 
-  // Splitting the number into its individual digits, then convert each digit to
-  // it's ASCII art representation
   for i in 0..HEIGHT {
-    for digit in number
-      .to_string()
-      .chars()
-      .map(|c| {
-        u64::from(c.to_digit(10).expect(
-          "couldn't convert character to digit, this should never happen",
-        ))
-      })
-      .collect::<Vec<_>>()
-    {
+    for piece in pieces {
       art.push_str(
-        to_art_digit(digit)
+        piece
           .lines()
           .nth(i)
-          .expect("invalid line from digit art, this should never happen"),
+          .expect("invalid line from art, this should never happen"),
       );
     }
 
@@ -142,45 +439,138 @@ fn number_to_art(number: u64) -> String {
   art
 }
 
-pub fn number_to_image(number: u64) -> Vec<u8> {
-  let art = number_to_art(number);
-  let mut image = vec![];
-
-  // Iterating over each character in the ASCII art, and converting it into a
-  // transparent or filled pixel
-  for pixel in art
-    // Replacing these characters with different characters isn't at all needed
-    // (except for the newline character), but it makes the following
-    // process a little more readable.
-    .replace(' ', "a")
-    .replace('█', "b")
-    .replace('\n', "")
-    .as_bytes()
-  {
-    if pixel == &97 {
-      // A transparent pixel
-      image.push(0);
-      image.push(0);
-      image.push(0);
-      image.push(0);
-    } else if pixel == &98 {
-      // A solid white pixel
-      image.push(255u8);
-      image.push(255u8);
-      image.push(255u8);
-      image.push(255u8);
-    } else {
-      unreachable!();
+/// Which halves of a character cell a character fills, top and bottom
+fn halves(character: char) -> (bool, bool) {
+  match character {
+    ' ' => (false, false),
+    '▀' => (true, false),
+    '▄' => (false, true),
+    '█' => (true, true),
+    _ => unreachable!("'{character}' isn't a block character"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::{Band, Style};
+
+  /// How big an icon can get, a bolt and three narrow digits across
+  const LARGEST: usize = 20;
+
+  fn glyphs() -> impl Iterator<Item = Glyph> {
+    (0..=100)
+      .flat_map(|percentage| {
+        [Glyph::Percent(percentage), Glyph::Charging(percentage)]
+      })
+      .chain([Glyph::Loading, Glyph::Error, Glyph::Disconnected])
+  }
+
+  #[test]
+  fn renders_every_glyph_within_bounds() {
+    for style in [Style::Digits, Style::Gauge] {
+      let renderer = Renderer::from_config(&IconConfig {
+        style,
+        ..IconConfig::default()
+      });
+
+      for glyph in glyphs() {
+        let image = lodepng::decode32(renderer.render(glyph)).unwrap();
+
+        assert_eq!(image.width, image.height, "{glyph:?} isn't square");
+        assert!(image.width <= LARGEST, "{glyph:?} is too big");
+        assert!(
+          image.buffer.iter().any(|pixel| pixel.a > 0),
+          "{glyph:?} is empty"
+        );
+      }
     }
   }
 
-  // Create an image from the pixel data
-  lodepng::encode_memory(
-    &image,
-    art.lines().next().unwrap().chars().count(),
-    HEIGHT,
-    lodepng::ColorType::RGBA,
-    8,
-  )
-  .unwrap_or_else(|_| panic!("unable to encode digit {number}"))
+  #[test]
+  fn switches_to_narrow_digits_for_three_digits() {
+    assert_eq!(digits(100, 0), [NARROW_ONE, NARROW_ZERO, NARROW_ZERO]);
+    assert_eq!(digits(57, 0), [FIVE, SEVEN]);
+    // The bolt takes up the room of a digit
+    assert_eq!(digits(57, 1), [NARROW_FIVE, NARROW_SEVEN]);
+    assert_eq!(digits(7, 1), [SEVEN]);
+  }
+
+  #[test]
+  fn paints_a_trimmed_and_padded_square() {
+    const RED: [u8; 4] = [255, 0, 0, 255];
+    let palette = Palette {
+      bands: vec![(0, RED)],
+      status: RED,
+      outline: None,
+      background: None,
+    };
+    // Two drawn columns between three empty ones on the left and one on the
+    // right, which are trimmed off before centering
+    let pixels = vec![vec![None, None, None, Some(RED), Some(RED), None]; 2];
+    let image = lodepng::decode32(paint(&pixels, &palette)).unwrap();
+    let drawn = (0..image.height)
+      .map(|y| {
+        (0..image.width)
+          .map(|x| image.buffer[y * image.width + x].a > 0)
+          .collect::<Vec<_>>()
+      })
+      .collect::<Vec<_>>();
+
+    assert_eq!(
+      drawn,
+      [
+        [false, false, false, false],
+        [false, true, true, false],
+        [false, true, true, false],
+        [false, false, false, false],
+      ]
+    );
+  }
+
+  #[test]
+  fn picks_the_band_a_battery_level_is_above() {
+    let traffic = Palette::from_config(&IconConfig {
+      preset: Preset::Traffic,
+      ..IconConfig::default()
+    });
+    let green = [76, 175, 80, 255];
+    let amber = [255, 193, 7, 255];
+    let red = [244, 67, 54, 255];
+
+    assert_eq!(traffic.foreground(Glyph::Percent(100)), green);
+    assert_eq!(traffic.foreground(Glyph::Charging(51)), green);
+    assert_eq!(traffic.foreground(Glyph::Percent(50)), amber);
+    assert_eq!(traffic.foreground(Glyph::Percent(20)), red);
+    assert_eq!(traffic.foreground(Glyph::Percent(0)), red);
+    assert_eq!(traffic.foreground(Glyph::Error), traffic.status());
+
+    // Bands replace the preset's, in whichever order they're written
+    let custom = Palette::from_config(&IconConfig {
+      preset: Preset::Traffic,
+      bands: Some(vec![
+        Band {
+          above: 10,
+          color: crate::config::Color(red),
+        },
+        Band {
+          above: 90,
+          color: crate::config::Color(green),
+        },
+      ]),
+      ..IconConfig::default()
+    });
+
+    assert_eq!(custom.foreground(Glyph::Percent(95)), green);
+    assert_eq!(custom.foreground(Glyph::Percent(50)), red);
+    assert_eq!(custom.foreground(Glyph::Percent(5)), red);
+  }
+
+  #[test]
+  fn fills_every_half_block() {
+    assert_eq!(halves(' '), (false, false));
+    assert_eq!(halves('▀'), (true, false));
+    assert_eq!(halves('▄'), (false, true));
+    assert_eq!(halves('█'), (true, true));
+  }
 }
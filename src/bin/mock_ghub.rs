@@ -0,0 +1,83 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A stand-in for the Logitech G HUB `WebSocket`, so that elem can be run and
+//! poked at without G HUB, Windows, or any Logitech hardware
+//!
+//! The mock is scripted one command per line over standard input, either by
+//! hand or from a file (`mock_ghub < script.txt`). Every command is listed in
+//! `src/mock_ghub.rs`, and on top of those, `quit` shuts the mock down. Blank
+//! lines and lines starting with `#` are skipped.
+
+#![deny(
+  warnings,
+  nonstandard_style,
+  unused,
+  future_incompatible,
+  rust_2018_idioms,
+  clippy::all,
+  clippy::nursery,
+  clippy::pedantic
+)]
+#![recursion_limit = "128"]
+
+#[macro_use]
+extern crate log;
+
+#[path = "../mock_ghub.rs"]
+mod mock_ghub;
+
+use std::io::BufRead;
+
+use mock_ghub::MockGhub;
+
+fn main() {
+  if std::env::var_os("RUST_LOG").is_none() {
+    std::env::set_var("RUST_LOG", "mock_ghub=trace");
+  }
+
+  pretty_env_logger::init();
+
+  let address = std::env::args()
+    .nth(1)
+    .unwrap_or_else(|| "127.0.0.1:9010".to_string());
+  let mock = MockGhub::bind(&address)
+    .unwrap_or_else(|e| panic!("failed to listen on {address}: {e}"));
+
+  info!("mocking logitech g hub on {}", mock.url());
+
+  for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+    let line = line.trim();
+
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    if line == "quit" {
+      return;
+    }
+
+    if let Err(e) = mock.run(line) {
+      warn!("skipping '{}': {}", line, e);
+    }
+  }
+
+  // Keep serving once the script has run out
+  loop {
+    std::thread::park();
+  }
+}
@@ -0,0 +1,424 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{collections::HashMap, sync::mpsc::Sender};
+
+use zbus::{
+  blocking::{Connection, Proxy},
+  zvariant::{OwnedObjectPath, OwnedValue},
+  MatchRule, Message, MessageType,
+};
+
+use crate::{
+  config::Bus,
+  dbus::Service,
+  source::{BatteryState, BatteryStateChange, DeviceInfo, Push},
+};
+
+const SERVICE: &str = "org.bluez";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const BATTERY_INTERFACE: &str = "org.bluez.Battery1";
+const OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+
+type ManagedObjects =
+  HashMap<OwnedObjectPath, HashMap<String, HashMap<String, OwnedValue>>>;
+
+/// The G HUB-style name of a `BlueZ` device icon
+///
+/// `BlueZ` doesn't have device types, but its icon names, which follow the
+/// freedesktop icon naming specification, are close enough.
+fn device_type(icon: &str) -> &'static str {
+  match icon {
+    "input-mouse" => "MOUSE",
+    "input-keyboard" => "KEYBOARD",
+    "input-gaming" => "GAMING_INPUT",
+    "input-tablet" => "TABLET",
+    "phone" => "PHONE",
+    "audio-headset" => "HEADSET",
+    "audio-headphones" => "HEADPHONES",
+    "audio-card" => "SPEAKERS",
+    _ => "UNKNOWN",
+  }
+}
+
+/// Connected Bluetooth devices which report their battery through the GATT
+/// Battery Service, as exposed by `BlueZ` (`org.bluez.Battery1`)
+///
+/// Each device's object path is used as its device ID.
+pub struct BlueZ {
+  service: Service,
+}
+
+impl BlueZ {
+  pub const fn new(bus: Bus) -> Self {
+    Self {
+      service: Service::new("bluez", bus),
+    }
+  }
+
+  fn proxy<'a>(
+    connection: &'a Connection,
+    path: &str,
+    interface: &'static str,
+  ) -> zbus::Result<Proxy<'a>> {
+    Proxy::new(connection, SERVICE, path.to_string(), interface)
+  }
+
+  fn is_connected(connection: &Connection, path: &str) -> zbus::Result<bool> {
+    Self::proxy(connection, path, DEVICE_INTERFACE)?
+      .get_property::<bool>("Connected")
+  }
+
+  fn battery_state(
+    connection: &Connection,
+    path: &str,
+  ) -> zbus::Result<BatteryState> {
+    let percentage = Self::proxy(connection, path, BATTERY_INTERFACE)?
+      .get_property::<u8>("Percentage")?;
+
+    // The Battery Service only ever reports a percentage
+    Ok(BatteryState::new(
+      u64::from(percentage),
+      false,
+      None,
+      false,
+      false,
+    ))
+  }
+
+  /// List every connected device with a battery
+  fn list(connection: &Connection) -> zbus::Result<Vec<DeviceInfo>> {
+    let objects =
+      Proxy::new(connection, SERVICE, "/", OBJECT_MANAGER_INTERFACE)?
+        .call::<_, _, ManagedObjects>("GetManagedObjects", &())?;
+    let mut devices = vec![];
+
+    for (path, interfaces) in objects {
+      if !interfaces.contains_key(BATTERY_INTERFACE)
+        || !interfaces.contains_key(DEVICE_INTERFACE)
+      {
+        continue;
+      }
+
+      // A device going away while it's being read shouldn't hide every other
+      // device
+      match Self::connected_device(connection, path.as_str()) {
+        Ok(Some(device_info)) => devices.push(device_info),
+        Ok(None) => {}
+        Err(e) => warn!("skipping unreadable bluez device {}: {}", path, e),
+      }
+    }
+
+    Ok(devices)
+  }
+
+  /// Describe a device, or nothing if it isn't connected
+  fn connected_device(
+    connection: &Connection,
+    path: &str,
+  ) -> zbus::Result<Option<DeviceInfo>> {
+    if !Self::is_connected(connection, path)? {
+      return Ok(None);
+    }
+
+    let device = Self::proxy(connection, path, DEVICE_INTERFACE)?;
+    // Aliases fall back to the device's name, or its address if it doesn't
+    // have one.
+    let alias = device.get_property::<String>("Alias")?;
+    let icon = device.get_property::<String>("Icon").unwrap_or_default();
+
+    Ok(Some(DeviceInfo::new(
+      path,
+      "BLUETOOTH",
+      device_type(&icon),
+      &alias,
+    )))
+  }
+
+  /// Turn devices coming and going, connecting and disconnecting, and
+  /// battery percentage changes into pushes
+  fn to_push(
+    connection: &Connection,
+    message: &Message,
+  ) -> zbus::Result<Option<Push>> {
+    match message.member().as_deref() {
+      Some("InterfacesAdded") => {
+        let (_, interfaces) = message.body::<(
+          OwnedObjectPath,
+          HashMap<String, HashMap<String, OwnedValue>>,
+        )>()?;
+
+        Ok(
+          interfaces
+            .contains_key(BATTERY_INTERFACE)
+            .then_some(Push::DeviceState),
+        )
+      }
+      Some("InterfacesRemoved") => {
+        let (_, interfaces) =
+          message.body::<(OwnedObjectPath, Vec<String>)>()?;
+
+        Ok(
+          interfaces
+            .iter()
+            .any(|interface| interface == BATTERY_INTERFACE)
+            .then_some(Push::DeviceState),
+        )
+      }
+      Some("PropertiesChanged") => {
+        let (interface, changed, _) =
+          message
+            .body::<(String, HashMap<String, OwnedValue>, Vec<String>)>()?;
+        let Some(path) = message.path() else {
+          return Ok(None);
+        };
+
+        match interface.as_str() {
+          DEVICE_INTERFACE if changed.contains_key("Connected") =>
+            Ok(Some(Push::DeviceState)),
+          BATTERY_INTERFACE if changed.contains_key("Percentage") =>
+            match Self::battery_state(connection, path.as_str()) {
+              Ok(state) => {
+                trace!("received battery state change for '{}'", path);
+
+                Ok(Some(Push::BatteryState(BatteryStateChange::new(
+                  path.as_str(),
+                  state,
+                ))))
+              }
+              Err(e) => {
+                debug!("skipping unreadable battery state change: {}", e);
+
+                Ok(None)
+              }
+            },
+          _ => Ok(None),
+        }
+      }
+      _ => Ok(None),
+    }
+  }
+}
+
+impl crate::source::BatterySource for BlueZ {
+  fn name(&self) -> &'static str { "bluez" }
+
+  fn is_connected(&self) -> bool { self.service.is_connected() }
+
+  fn devices(&mut self) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    self.service.devices(Self::list)
+  }
+
+  fn battery(&mut self, id: &str) -> crate::source::Result<BatteryState> {
+    let connection = self.service.connection()?;
+
+    if !Self::is_connected(connection, id)? {
+      return Err(format!("'{id}' is not connected").into());
+    }
+
+    Ok(Self::battery_state(connection, id)?)
+  }
+
+  fn subscribe(&mut self, sender: Sender<Push>) {
+    // This will never fail because `SERVICE` is a valid bus name.
+    let rule = MatchRule::builder()
+      .msg_type(MessageType::Signal)
+      .sender(SERVICE)
+      .unwrap()
+      .build();
+
+    self.service.subscribe(rule, Self::to_push, sender);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc;
+
+  use zbus::dbus_interface;
+
+  use super::*;
+  use crate::{
+    dbus::tests::{push_from, PrivateBus},
+    source::BatterySource,
+  };
+
+  const MOUSE: &str = "/org/bluez/hci0/dev_00_11_22_33_44_55";
+  const HEADSET: &str = "/org/bluez/hci0/dev_66_77_88_99_AA_BB";
+  const SPEAKER: &str = "/org/bluez/hci0/dev_CC_DD_EE_FF_00_11";
+  const KEYBOARD: &str = "/org/bluez/hci0/dev_22_33_44_55_66_77";
+
+  /// A stand-in for a device `BlueZ` knows about
+  struct Device {
+    alias: &'static str,
+    icon: &'static str,
+    connected: bool,
+  }
+
+  #[dbus_interface(name = "org.bluez.Device1")]
+  impl Device {
+    #[dbus_interface(property)]
+    fn alias(&self) -> String { self.alias.to_string() }
+
+    #[dbus_interface(property)]
+    fn icon(&self) -> String { self.icon.to_string() }
+
+    #[dbus_interface(property)]
+    fn connected(&self) -> bool { self.connected }
+  }
+
+  /// A stand-in for a device which can't be read, like one going away while
+  /// it's being listed
+  struct Unreadable {
+    path: &'static str,
+  }
+
+  #[dbus_interface(name = "org.bluez.Device1")]
+  impl Unreadable {
+    #[dbus_interface(property)]
+    fn connected(&self) -> zbus::fdo::Result<bool> {
+      Err(zbus::fdo::Error::UnknownObject(self.path.to_string()))
+    }
+  }
+
+  /// A stand-in for a device's Battery Service
+  struct Battery {
+    percentage: u8,
+  }
+
+  #[dbus_interface(name = "org.bluez.Battery1")]
+  impl Battery {
+    #[dbus_interface(property)]
+    fn percentage(&self) -> u8 { self.percentage }
+  }
+
+  /// Serve a connected mouse with a battery, a disconnected headset with one,
+  /// a connected speaker without one, and a keyboard with one which can't be
+  /// read
+  fn serve(bus: &PrivateBus) -> Connection {
+    let connection = bus.serve(SERVICE).build().unwrap();
+    let server = connection.object_server();
+
+    // Objects are only served once the connection is up, since the object
+    // manager announces each of them as they're added
+    server.at("/", zbus::fdo::ObjectManager).unwrap();
+    server
+      .at(
+        MOUSE,
+        Device {
+          alias: "MX Anywhere 3",
+          icon: "input-mouse",
+          connected: true,
+        },
+      )
+      .unwrap();
+    server.at(MOUSE, Battery { percentage: 64 }).unwrap();
+    server
+      .at(
+        HEADSET,
+        Device {
+          alias: "WH-1000XM4",
+          icon: "audio-headset",
+          connected: false,
+        },
+      )
+      .unwrap();
+    server.at(HEADSET, Battery { percentage: 80 }).unwrap();
+    server
+      .at(
+        SPEAKER,
+        Device {
+          alias: "SoundLink",
+          icon: "audio-card",
+          connected: true,
+        },
+      )
+      .unwrap();
+    server.at(KEYBOARD, Unreadable { path: KEYBOARD }).unwrap();
+    server.at(KEYBOARD, Battery { percentage: 30 }).unwrap();
+    drop(server);
+
+    connection
+  }
+
+  #[test]
+  fn lists_only_connected_devices_with_a_battery() {
+    let bus = PrivateBus::start();
+    let _bluez = serve(&bus);
+    let devices = BlueZ::new(bus.bus()).devices().unwrap();
+
+    assert_eq!(devices.keys().collect::<Vec<_>>(), [MOUSE]);
+    assert_eq!(devices[MOUSE].label, "MX Anywhere 3");
+    assert_eq!(devices[MOUSE].device_type(), "MOUSE");
+    assert_eq!(devices[MOUSE].connection_type(), "BLUETOOTH");
+  }
+
+  #[test]
+  fn reads_the_battery_percentage() {
+    let bus = PrivateBus::start();
+    let _bluez = serve(&bus);
+    let mut bluez = BlueZ::new(bus.bus());
+
+    assert_eq!(bluez.battery(MOUSE).unwrap().percentage(), 64);
+    assert!(bluez.battery(HEADSET).is_err());
+  }
+
+  #[test]
+  fn pushes_battery_and_connection_changes() {
+    let bus = PrivateBus::start();
+    let connection = serve(&bus);
+    let (sender, pushes) = mpsc::channel();
+    let battery = connection
+      .object_server()
+      .interface::<_, Battery>(MOUSE)
+      .unwrap();
+    let headset = connection
+      .object_server()
+      .interface::<_, Device>(HEADSET)
+      .unwrap();
+
+    BlueZ::new(bus.bus()).subscribe(sender);
+    battery.get_mut().percentage = 12;
+    push_from(
+      || {
+        zbus::block_on(
+          battery.get().percentage_changed(battery.signal_context()),
+        )
+        .unwrap();
+      },
+      &pushes,
+      |push| {
+        matches!(
+          push,
+          Push::BatteryState(change)
+            if change.device_id() == MOUSE && change.state().percentage() == 12
+        )
+      },
+    );
+    headset.get_mut().connected = true;
+    push_from(
+      || {
+        zbus::block_on(
+          headset.get().connected_changed(headset.signal_context()),
+        )
+        .unwrap();
+      },
+      &pushes,
+      |push| matches!(push, Push::DeviceState),
+    );
+  }
+}
@@ -0,0 +1,94 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+/// How elem is run from the command-line
+pub const USAGE: &str = "usage: elem [--ghub-url <url>] [update frequency in \
+                         ms]
+
+  --ghub-url <url>  reach logitech g hub at <url> instead of the configured url
+  --help            show this message";
+
+/// Everything elem was told from the command-line
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Args {
+  /// Left for the tray to parse, since it falls back to its default by itself
+  pub update_frequency: Option<String>,
+  pub ghub_url: Option<String>,
+  pub help: bool,
+}
+
+impl Args {
+  /// Parse the command-line arguments, without the program name, refusing
+  /// anything elem doesn't know about
+  pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+    let mut parsed = Self::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+      if let Some(url) = arg.strip_prefix("--ghub-url=") {
+        parsed.ghub_url = Some(url.to_string());
+      } else if arg == "--ghub-url" {
+        parsed.ghub_url =
+          Some(args.next().ok_or("--ghub-url is missing a url")?);
+      } else if arg == "--help" || arg == "-h" {
+        parsed.help = true;
+      } else if arg.starts_with('-') {
+        return Err(format!("unknown flag '{arg}'"));
+      } else if parsed.update_frequency.is_some() {
+        return Err(format!("unexpected argument '{arg}'"));
+      } else {
+        // Anything that isn't a flag is the update frequency
+        parsed.update_frequency = Some(arg);
+      }
+    }
+
+    Ok(parsed)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(args: &[&str]) -> Result<Args, String> {
+    Args::parse(args.iter().map(ToString::to_string))
+  }
+
+  #[test]
+  fn takes_the_ghub_url_either_way() {
+    let args = parse(&["--ghub-url", "ws://10.0.0.2:9010", "1000"]).unwrap();
+
+    assert_eq!(args.ghub_url.as_deref(), Some("ws://10.0.0.2:9010"));
+    assert_eq!(args.update_frequency.as_deref(), Some("1000"));
+    assert_eq!(parse(&["1000", "--ghub-url=ws://10.0.0.2:9010"]), Ok(args));
+  }
+
+  #[test]
+  fn refuses_anything_unknown() {
+    assert!(parse(&["--ghub-url"]).is_err());
+    assert!(parse(&["--ghub_url=ws://localhost:9010"]).is_err());
+    assert!(parse(&["-v"]).is_err());
+    assert!(parse(&["1000", "2000"]).is_err());
+  }
+
+  #[test]
+  fn asks_for_help() {
+    assert!(parse(&["--help"]).unwrap().help);
+    assert!(parse(&["-h", "1000"]).unwrap().help);
+    assert_eq!(parse(&[]), Ok(Args::default()));
+  }
+}
@@ -0,0 +1,563 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+  collections::HashMap,
+  io::BufRead,
+  path::PathBuf,
+  process::{Child, Command, Stdio},
+  sync::{mpsc::Sender, Arc, Condvar, Mutex, MutexGuard},
+  time::Duration,
+};
+
+use serde_derive::Deserialize;
+
+use crate::source::{BatteryState, BatteryStateChange, DeviceInfo, Push};
+
+/// How long a freshly started program gets to report its devices before
+/// they're listed, so that it isn't taken for one without any
+const STARTUP_GRACE: Duration = Duration::from_secs(2);
+
+/// A single line of the program's output
+///
+/// Devices and battery states look exactly like the ones G HUB sends, with a
+/// `type` added to tell them apart.
+///
+/// ```json
+/// {"type": "device", "id": "kb", "deviceType": "KEYBOARD", "displayName": "Keyboard"}
+/// {"type": "battery", "deviceId": "kb", "percentage": 80, "charging": true}
+/// {"type": "removed", "id": "kb"}
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+  /// A device was added, or its details changed
+  Device(DeviceInfo),
+  /// A device's battery state changed
+  Battery(BatteryStateChange),
+  /// A device is gone
+  Removed { id: String },
+}
+
+/// Everything the program has told us so far
+#[derive(Default)]
+struct Records {
+  devices: HashMap<String, DeviceInfo>,
+  batteries: HashMap<String, BatteryState>,
+  running: bool,
+  /// Whether the program was started and hasn't reported anything yet
+  starting: bool,
+  sender: Option<Sender<Push>>,
+}
+
+impl Records {
+  /// Forward a push, forgetting the sender once its receiver is gone
+  fn push(&mut self, push: Push) {
+    if let Some(ref sender) = self.sender {
+      if sender.send(push).is_err() {
+        self.sender = None;
+      }
+    }
+  }
+}
+
+/// The running program, which is killed and waited on once dropped, so that
+/// it's never left running or unreaped, whatever stopped it being read
+struct Running {
+  child: Child,
+  /// The job which kills the program if elem exits first
+  #[cfg(windows)]
+  job: winapi::um::winnt::HANDLE,
+}
+
+impl Running {
+  /// Start the program, making sure it doesn't outlive elem however elem exits
+  #[cfg(target_os = "linux")]
+  fn spawn(command: &mut Command) -> std::io::Result<Self> {
+    use std::os::unix::process::CommandExt;
+
+    // The program is sent SIGTERM once the thread which started it exits,
+    // which, as the thread is only ever done reading the program once elem
+    // exits, is once elem exits.
+    unsafe {
+      command.pre_exec(|| {
+        if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+          return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(())
+      });
+    }
+
+    Ok(Self {
+      child: command.spawn()?,
+    })
+  }
+
+  /// Start the program, making sure it doesn't outlive elem however elem exits
+  #[cfg(windows)]
+  fn spawn(command: &mut Command) -> std::io::Result<Self> {
+    use std::os::windows::process::CommandExt;
+
+    // The program only starts running once it's in the job, so that anything
+    // it starts straight away ends up in the job too.
+    let mut child = command
+      .creation_flags(winapi::um::winbase::CREATE_SUSPENDED)
+      .spawn()?;
+    let job = unsafe { kill_on_close(&child) }.and_then(|job| {
+      match unsafe { resume(child.id()) } {
+        Ok(()) => Ok(job),
+        Err(e) => {
+          unsafe { winapi::um::handleapi::CloseHandle(job) };
+
+          Err(e)
+        }
+      }
+    });
+
+    match job {
+      Ok(job) => Ok(Self { child, job }),
+      Err(e) => {
+        // The program never got to run, so there's nothing to lose
+        child.kill().ok();
+        child.wait().ok();
+
+        Err(e)
+      }
+    }
+  }
+
+  /// Start the program, which is left running if elem exits first, since
+  /// there's no way to tie it to elem here
+  #[cfg(not(any(target_os = "linux", windows)))]
+  fn spawn(command: &mut Command) -> std::io::Result<Self> {
+    Ok(Self {
+      child: command.spawn()?,
+    })
+  }
+}
+
+impl Drop for Running {
+  fn drop(&mut self) {
+    // The program might have already exited on its own, in which case there's
+    // nothing to kill.
+    self.child.kill().ok();
+    self.child.wait().ok();
+
+    // The program is gone, so closing the job doesn't kill anything.
+    #[cfg(windows)]
+    unsafe {
+      winapi::um::handleapi::CloseHandle(self.job);
+    }
+  }
+}
+
+/// Put a program in a job of its own, which Windows closes once elem exits,
+/// killing everything in it
+#[cfg(windows)]
+unsafe fn kill_on_close(
+  child: &Child,
+) -> std::io::Result<winapi::um::winnt::HANDLE> {
+  use std::os::windows::io::AsRawHandle;
+
+  use winapi::um::{
+    handleapi::CloseHandle,
+    jobapi2::{
+      AssignProcessToJobObject, CreateJobObjectW, SetInformationJobObject,
+    },
+    winnt::{
+      JobObjectExtendedLimitInformation, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+      JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    },
+  };
+
+  let job = CreateJobObjectW(std::ptr::null_mut(), std::ptr::null());
+  let mut limits = std::mem::zeroed::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>();
+
+  if job.is_null() {
+    return Err(std::io::Error::last_os_error());
+  }
+
+  limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+
+  // The limits are a fixed size struct, well under four gigabytes
+  #[allow(clippy::cast_possible_truncation)]
+  let assigned = SetInformationJobObject(
+    job,
+    JobObjectExtendedLimitInformation,
+    std::ptr::addr_of_mut!(limits).cast(),
+    std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+  ) != 0
+    && AssignProcessToJobObject(job, child.as_raw_handle().cast()) != 0;
+
+  if !assigned {
+    let e = std::io::Error::last_os_error();
+
+    CloseHandle(job);
+
+    return Err(e);
+  }
+
+  Ok(job)
+}
+
+/// Resume a program started suspended
+///
+/// `Command` doesn't hand out the handle of the program's main thread, so it's
+/// looked up among every thread running, where it's the program's only one.
+#[cfg(windows)]
+unsafe fn resume(process_id: u32) -> std::io::Result<()> {
+  use winapi::um::{
+    handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
+    processthreadsapi::{OpenThread, ResumeThread},
+    tlhelp32::{
+      CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD,
+      THREADENTRY32,
+    },
+    winnt::THREAD_SUSPEND_RESUME,
+  };
+
+  let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0);
+  let mut entry = std::mem::zeroed::<THREADENTRY32>();
+  let mut result = Err(std::io::Error::new(
+    std::io::ErrorKind::NotFound,
+    "the program's thread wasn't found",
+  ));
+
+  if snapshot == INVALID_HANDLE_VALUE {
+    return Err(std::io::Error::last_os_error());
+  }
+
+  // The entry is a fixed size struct, well under four gigabytes
+  #[allow(clippy::cast_possible_truncation)]
+  {
+    entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+  }
+
+  let mut listed = Thread32First(snapshot, std::ptr::addr_of_mut!(entry)) != 0;
+
+  while listed {
+    if entry.th32OwnerProcessID == process_id {
+      let thread = OpenThread(THREAD_SUSPEND_RESUME, 0, entry.th32ThreadID);
+
+      result = if thread.is_null() || ResumeThread(thread) == u32::MAX {
+        Err(std::io::Error::last_os_error())
+      } else {
+        Ok(())
+      };
+
+      if !thread.is_null() {
+        CloseHandle(thread);
+      }
+
+      break;
+    }
+
+    listed = Thread32Next(snapshot, std::ptr::addr_of_mut!(entry)) != 0;
+  }
+
+  CloseHandle(snapshot);
+
+  result
+}
+
+/// Devices reported by a user-provided program, one JSON record per line of
+/// its output
+///
+/// The program is started the first time anything is asked of the source, and
+/// started again whenever it exits. Anything it prints to stderr ends up in
+/// elem's own log.
+pub struct ExternalCommand {
+  program: PathBuf,
+  args: Vec<String>,
+  records: Arc<Mutex<Records>>,
+  /// Signalled once a freshly started program reports something, or exits
+  reported: Arc<Condvar>,
+  started: bool,
+}
+
+impl ExternalCommand {
+  pub fn new(program: &std::path::Path, args: &[String]) -> Self {
+    Self {
+      program: program.to_path_buf(),
+      args: args.to_vec(),
+      records: Arc::new(Mutex::new(Records::default())),
+      reported: Arc::new(Condvar::new()),
+      started: false,
+    }
+  }
+
+  /// Start the program if it isn't running yet, and get everything it has
+  /// reported so far, giving it a moment to report anything if it was only
+  /// just started
+  fn records(&mut self) -> MutexGuard<'_, Records> {
+    self.start();
+
+    self
+      .reported
+      .wait_timeout_while(
+        self.records.lock().unwrap(),
+        STARTUP_GRACE,
+        |records| records.starting,
+      )
+      .unwrap()
+      .0
+  }
+
+  /// Start the program in the background, unless it already is
+  fn start(&mut self) {
+    if self.started {
+      return;
+    }
+
+    let program = self.program.clone();
+    let args = self.args.clone();
+    let records = self.records.clone();
+    let reported = self.reported.clone();
+
+    self.started = true;
+    // Set before the program is even started, so that nothing is listed
+    // before it has had the chance to report anything
+    self.records.lock().unwrap().starting = true;
+
+    std::thread::spawn(move || {
+      let mut backoff = crate::source::Backoff::default();
+
+      loop {
+        match Self::read(&program, &args, &records, &reported) {
+          Ok(true) => {
+            warn!("{:?} exited, restarting it", program);
+
+            backoff = crate::source::Backoff::default();
+          }
+          Ok(false) => warn!("{:?} exited without any output", program),
+          Err(e) => warn!("failed to run {:?}: {}", program, e),
+        }
+
+        {
+          let mut records = records.lock().unwrap();
+
+          // Whatever the program reported is stale now that it's gone
+          records.running = false;
+          records.starting = false;
+          records.devices.clear();
+          records.batteries.clear();
+          records.push(Push::DeviceState);
+        }
+
+        reported.notify_all();
+
+        backoff.wait();
+      }
+    });
+  }
+
+  /// Run the program until it exits, returning whether it printed anything
+  fn read(
+    program: &std::path::Path,
+    args: &[String],
+    records: &Mutex<Records>,
+    reported: &Condvar,
+  ) -> std::io::Result<bool> {
+    let mut running = Running::spawn(
+      Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped()),
+    )?;
+    // This will never fail because stdout was just piped
+    let stdout = running.child.stdout.take().unwrap();
+    let mut printed = false;
+
+    debug!("started {:?}", program);
+
+    {
+      let mut records = records.lock().unwrap();
+
+      records.running = true;
+      records.starting = true;
+    }
+
+    for line in std::io::BufReader::new(stdout).lines() {
+      let line = line?;
+
+      if line.trim().is_empty() {
+        continue;
+      }
+
+      let record = match serde_json::from_str::<Record>(&line) {
+        Ok(record) => record,
+        Err(e) => {
+          warn!("skipping malformed record from {:?}: {}", program, e);
+
+          continue;
+        }
+      };
+      let mut records = records.lock().unwrap();
+
+      trace!("received {:?}", record);
+
+      printed = true;
+      records.starting = false;
+
+      match record {
+        Record::Device(device) => {
+          records.devices.insert(device.id.clone(), device);
+          records.push(Push::DeviceState);
+        }
+        Record::Battery(change) => {
+          records
+            .batteries
+            .insert(change.device_id().to_string(), change.state().clone());
+          records.push(Push::BatteryState(change));
+        }
+        Record::Removed { id } => {
+          records.devices.remove(&id);
+          records.batteries.remove(&id);
+          records.push(Push::DeviceState);
+        }
+      }
+
+      drop(records);
+      reported.notify_all();
+    }
+
+    running.child.wait()?;
+
+    Ok(printed)
+  }
+}
+
+impl crate::source::BatterySource for ExternalCommand {
+  fn name(&self) -> &'static str { "external command" }
+
+  fn is_connected(&self) -> bool { self.records.lock().unwrap().running }
+
+  fn devices(&mut self) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    Ok(crate::source::label_devices(
+      self
+        .records()
+        .devices
+        .values()
+        .map(DeviceInfo::from_device_info)
+        .collect(),
+    ))
+  }
+
+  fn battery(&mut self, id: &str) -> crate::source::Result<BatteryState> {
+    let program = self.program.display().to_string();
+    let records = self.records();
+
+    if !records.devices.contains_key(id) {
+      return Err(format!("{program} reported no device '{id}'").into());
+    }
+
+    records.batteries.get(id).cloned().ok_or_else(|| {
+      format!("{program} hasn't reported a battery state for '{id}' yet").into()
+    })
+  }
+
+  fn subscribe(&mut self, sender: Sender<Push>) {
+    let mut records = self.records.lock().unwrap();
+
+    // Anything reported before subscribing would otherwise go unnoticed until
+    // the next refresh
+    if !records.devices.is_empty() {
+      let _ = sender.send(Push::DeviceState);
+    }
+
+    records.sender = Some(sender);
+    drop(records);
+    self.start();
+  }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use std::time::Instant;
+
+  use super::*;
+  use crate::source::BatterySource;
+
+  /// Run a shell script as the program
+  fn script(script: &str) -> ExternalCommand {
+    ExternalCommand::new(
+      std::path::Path::new("sh"),
+      &["-c".to_string(), script.to_string()],
+    )
+  }
+
+  #[test]
+  fn lists_devices_reported_shortly_after_starting() {
+    let mut command = script(
+      r#"sleep 0.2
+echo '{"type": "device", "id": "kb", "deviceType": "KEYBOARD", "displayName": "Keyboard"}'
+echo '{"type": "battery", "deviceId": "kb", "percentage": 80, "charging": true}'
+exec sleep 60"#,
+    );
+
+    assert_eq!(
+      command.devices().unwrap().keys().collect::<Vec<_>>(),
+      ["kb"]
+    );
+  }
+
+  #[test]
+  fn lists_nothing_once_the_program_exits_silently() {
+    let started = Instant::now();
+
+    assert!(script("exit 0").devices().unwrap().is_empty());
+    assert!(started.elapsed() < STARTUP_GRACE);
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn reaps_the_program_once_its_output_is_unreadable() {
+    let pid =
+      std::env::temp_dir().join(format!("elem-command-{}", std::process::id()));
+    let mut command = script(&format!(
+      r"echo $$ > {}; printf '\377\n'; exec sleep 60",
+      pid.display()
+    ));
+
+    // The records are cleared once reading fails, which wakes this up
+    command.devices().unwrap();
+
+    let started = Instant::now();
+    let pid = loop {
+      if let Ok(written) = std::fs::read_to_string(&pid) {
+        if !written.trim().is_empty() {
+          std::fs::remove_file(&pid).ok();
+
+          break written.trim().to_string();
+        }
+      }
+
+      assert!(started.elapsed() < Duration::from_secs(5));
+      std::thread::sleep(Duration::from_millis(20));
+    };
+
+    // Neither running nor a zombie, the process is gone entirely once reaped
+    while std::path::Path::new("/proc").join(&pid).exists() {
+      assert!(
+        started.elapsed() < Duration::from_secs(5),
+        "{pid} was never reaped"
+      );
+      std::thread::sleep(Duration::from_millis(20));
+    }
+  }
+}
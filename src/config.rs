@@ -0,0 +1,458 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::path::PathBuf;
+
+use serde_derive::Deserialize;
+
+/// The environment variable pointing to elem's configuration file
+const CONFIG_VARIABLE: &str = "ELEM_CONFIG";
+/// The environment variable overriding the configured G HUB URL
+const GHUB_URL_VARIABLE: &str = "ELEM_GHUB_URL";
+
+/// The battery sources elem knows how to read from
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+  /// Logitech G HUB
+  Logitech,
+  /// The Linux kernel's power supplies, as found in sysfs
+  Sysfs,
+  /// `UPower`, over D-Bus
+  UPower,
+  /// Logitech devices, directly over HID++ 2.0
+  Hidpp,
+  /// Whatever a user-provided program reports
+  Command,
+  /// Bluetooth devices known to `BlueZ`, over D-Bus
+  BlueZ,
+  /// Phones and tablets paired with KDE Connect, over D-Bus
+  KdeConnect,
+  /// Fake devices, for demos and trying things out
+  Simulation,
+}
+
+/// Which D-Bus bus to find a service on
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+  #[default]
+  System,
+  /// Useful for pointing elem at a stand-in service on a private session bus
+  Session,
+  /// Any other bus, by its address, like `unix:path=/tmp/bus`
+  #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+  Address(String),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct SysfsConfig {
+  /// Where the power supplies are listed
+  pub root: PathBuf,
+}
+
+impl Default for SysfsConfig {
+  fn default() -> Self {
+    Self {
+      root: PathBuf::from("/sys/class/power_supply"),
+    }
+  }
+}
+
+/// elem's configuration, read from the JSON file that `ELEM_CONFIG` points to
+///
+/// Everything is optional, and anything left out falls back to its default.
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct Config {
+  /// Which battery sources to read from, all at once
+  pub sources: Vec<SourceKind>,
+  pub logitech: LogitechConfig,
+  pub sysfs: SysfsConfig,
+  pub upower: UPowerConfig,
+  pub hidpp: HidppConfig,
+  pub command: CommandConfig,
+  pub bluez: BlueZConfig,
+  pub kdeconnect: KdeConnectConfig,
+  pub simulation: SimulationConfig,
+  pub icon: IconConfig,
+}
+
+/// A color, written as `#rrggbb`, or `#rrggbbaa` to make it see-through
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Color(pub [u8; 4]);
+
+impl TryFrom<String> for Color {
+  type Error = String;
+
+  fn try_from(color: String) -> Result<Self, Self::Error> {
+    let invalid = || format!("'{color}' isn't a #rrggbb or #rrggbbaa color");
+    let hex = color
+      .strip_prefix('#')
+      // `from_str_radix` would take a sign in front of a channel, too
+      .filter(|hex| {
+        (hex.len() == 6 || hex.len() == 8)
+          && hex.chars().all(|digit| digit.is_ascii_hexdigit())
+      })
+      .ok_or_else(invalid)?;
+    // Colors are opaque unless they say otherwise
+    let mut rgba = [255; 4];
+
+    for (i, channel) in rgba.iter_mut().enumerate().take(hex.len() / 2) {
+      *channel = hex
+        .get(i * 2..i * 2 + 2)
+        .and_then(|channel| u8::from_str_radix(channel, 16).ok())
+        .ok_or_else(invalid)?;
+    }
+
+    Ok(Self(rgba))
+  }
+}
+
+/// The palettes elem comes with
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+  /// Everything in white, for dark taskbars
+  #[default]
+  White,
+  /// Everything in black, for light taskbars
+  Black,
+  /// Green, amber, and red
+  Traffic,
+  /// Blue, orange, and vermillion, from Okabe and Ito's colorblind-safe
+  /// palette
+  OkabeIto,
+  /// Blue, gold, and magenta, from IBM's colorblind-safe palette
+  Ibm,
+}
+
+/// A foreground color used for battery levels above `above`
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct Band {
+  pub above: u8,
+  pub color: Color,
+}
+
+/// How battery levels are drawn
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Style {
+  /// The battery level, written out in digits
+  #[default]
+  Digits,
+  /// A battery whose fill bar is as full as the battery is
+  Gauge,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct GaugeConfig {
+  /// Whether to draw a bolt over the gauge while the device is charging
+  pub bolt: bool,
+  /// A color to fill the gauge with at or below `warning_below` percent,
+  /// instead of the palette's
+  pub warning: Option<Color>,
+  pub warning_below: u8,
+}
+
+impl Default for GaugeConfig {
+  fn default() -> Self {
+    Self {
+      bolt: true,
+      warning: None,
+      warning_below: 20,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct IconConfig {
+  pub style: Style,
+  pub gauge: GaugeConfig,
+  /// The palette to start from, which anything below overrides
+  pub preset: Preset,
+  /// Foreground colors by battery level
+  pub bands: Option<Vec<Band>>,
+  /// The color of glyphs which don't show a battery level, like the cross
+  pub status: Option<Color>,
+  /// A color to outline glyphs with, so they stand out on any taskbar
+  pub outline: Option<Color>,
+  /// A color to fill the rest of the icon with
+  pub background: Option<Color>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LogitechConfig {
+  /// Where G HUB's `WebSocket` is, like a forwarded G HUB on another machine
+  pub url: String,
+  /// How long to wait on G HUB to accept a connection, in milliseconds
+  pub connect_timeout_ms: u64,
+  /// How long to wait on G HUB to reply to a request, in milliseconds
+  pub read_timeout_ms: u64,
+}
+
+impl Default for LogitechConfig {
+  fn default() -> Self {
+    Self {
+      url: "ws://localhost:9010".to_string(),
+      connect_timeout_ms: 5000,
+      read_timeout_ms: 5000,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct HidppConfig {
+  /// A directory of recordings to replay instead of talking to real devices,
+  /// one per hidraw device
+  pub replay: Option<PathBuf>,
+  /// A directory to record every hidraw device's reports to
+  pub record: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct CommandConfig {
+  /// The program to run, which prints a JSON record per line
+  pub program: Option<PathBuf>,
+  pub args: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct UPowerConfig {
+  pub bus: Bus,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct BlueZConfig {
+  pub bus: Bus,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct KdeConnectConfig {
+  pub bus: Bus,
+}
+
+impl Default for KdeConnectConfig {
+  fn default() -> Self {
+    Self {
+      // KDE Connect runs per user, so it's found on the session bus
+      bus: Bus::Session,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SimulatedDeviceConfig {
+  /// Falls back to the device's position in the list if left out
+  pub id: String,
+  pub name: String,
+  #[serde(rename = "type")]
+  pub device_type: String,
+  /// The percentage the device starts at
+  pub percentage: f64,
+  /// Whether the device starts out plugged in
+  pub charging: bool,
+  /// `[seconds, percentage]` points the battery follows while discharging
+  pub discharge: Vec<(f64, f64)>,
+  /// `[seconds, percentage]` points the battery follows while charging
+  pub charge: Vec<(f64, f64)>,
+  /// How often the device randomly disconnects, on average
+  pub disconnects_per_hour: f64,
+  /// How long the device stays disconnected for
+  pub disconnect_seconds: u64,
+  /// How often the device randomly gets plugged in, on average
+  pub charges_per_hour: f64,
+}
+
+impl Default for SimulatedDeviceConfig {
+  fn default() -> Self {
+    Self {
+      id: String::new(),
+      name: "Simulated Mouse".to_string(),
+      device_type: "MOUSE".to_string(),
+      percentage: 100.0,
+      charging: false,
+      // Roughly a day of use, dropping off quicker towards the end like
+      // lithium batteries do
+      discharge: vec![(0.0, 100.0), (72_000.0, 20.0), (86_400.0, 0.0)],
+      // Fast charging up to 80%, then trickle charging the rest of the way
+      charge: vec![(0.0, 0.0), (3_600.0, 80.0), (5_400.0, 100.0)],
+      disconnects_per_hour: 0.0,
+      disconnect_seconds: 60,
+      charges_per_hour: 0.0,
+    }
+  }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct SimulationConfig {
+  /// Seeds random disconnects and charging, so that the same seed always
+  /// plays out the same way
+  pub seed: u64,
+  /// How many simulated seconds pass every real second
+  pub speed: f64,
+  pub devices: Vec<SimulatedDeviceConfig>,
+}
+
+impl Default for SimulationConfig {
+  fn default() -> Self {
+    Self {
+      seed: 0,
+      speed: 1.0,
+      devices: vec![SimulatedDeviceConfig::default()],
+    }
+  }
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      // G HUB only runs on Windows, and sysfs only exists on Linux
+      sources: if cfg!(windows) {
+        vec![SourceKind::Logitech]
+      } else {
+        vec![SourceKind::Sysfs]
+      },
+      logitech: LogitechConfig::default(),
+      sysfs: SysfsConfig::default(),
+      upower: UPowerConfig::default(),
+      hidpp: HidppConfig::default(),
+      command: CommandConfig::default(),
+      bluez: BlueZConfig::default(),
+      kdeconnect: KdeConnectConfig::default(),
+      simulation: SimulationConfig::default(),
+      icon: IconConfig::default(),
+    }
+  }
+}
+
+impl Config {
+  /// Load the configuration, letting the environment override the
+  /// configuration file
+  pub fn load() -> Self {
+    let mut config = Self::read();
+
+    if let Ok(url) = std::env::var(GHUB_URL_VARIABLE) {
+      debug!(
+        "using logitech g hub url from {}: {}",
+        GHUB_URL_VARIABLE, url
+      );
+
+      config.logitech.url = url;
+    }
+
+    config
+  }
+
+  /// Read the configuration file, falling back to the default configuration
+  /// if there isn't one or it can't be read
+  fn read() -> Self {
+    let Some(path) = std::env::var_os(CONFIG_VARIABLE) else {
+      debug!(
+        "{} is not set, using default configuration",
+        CONFIG_VARIABLE
+      );
+
+      return Self::default();
+    };
+    let config = std::fs::read_to_string(&path)
+      .map_err(|e| e.to_string())
+      .and_then(|config| {
+        serde_json::from_str::<Self>(&config).map_err(|e| e.to_string())
+      });
+
+    match config {
+      Ok(config) => {
+        debug!("using configuration from {:?}: {:?}", path, config);
+
+        config
+      }
+      Err(e) => {
+        warn!(
+          "failed to read configuration from {:?}, using default \
+           configuration: {}",
+          path, e
+        );
+
+        Self::default()
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn color(color: &str) -> Result<Color, String> {
+    Color::try_from(color.to_string())
+  }
+
+  #[test]
+  fn parses_colors() {
+    assert_eq!(color("#4caf50"), Ok(Color([76, 175, 80, 255])));
+    assert_eq!(color("#4CAF5080"), Ok(Color([76, 175, 80, 128])));
+
+    for invalid in ["4caf50", "#4caf5", "#4caf500", "#4caf5g", "#+f+f+f", "#"] {
+      assert!(color(invalid).is_err(), "{invalid} was accepted");
+    }
+  }
+
+  #[test]
+  fn reads_presets_and_bands() {
+    let config = serde_json::from_str::<IconConfig>(
+      r##"{
+        "preset": "okabe-ito",
+        "bands": [
+          { "above": 20, "color": "#ffc107" },
+          { "above": 50, "color": "#4caf50" }
+        ],
+        "outline": "#00000080"
+      }"##,
+    )
+    .unwrap();
+    let bands = config.bands.unwrap();
+
+    assert!(matches!(config.preset, Preset::OkabeIto));
+    assert_eq!(
+      (bands[0].above, bands[0].color),
+      (20, Color([255, 193, 7, 255]))
+    );
+    assert_eq!(bands[1].above, 50);
+    assert_eq!(config.outline, Some(Color([0, 0, 0, 128])));
+    assert!(config.status.is_none());
+    assert!(
+      serde_json::from_str::<IconConfig>(r#"{ "preset": "sepia" }"#).is_err()
+    );
+    assert!(serde_json::from_str::<IconConfig>(
+      r##"{ "bands": [{ "above": 50, "color": "#+f+f+f" }] }"##
+    )
+    .is_err());
+  }
+}
@@ -0,0 +1,245 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{collections::HashMap, sync::mpsc::Sender};
+
+use zbus::{
+  blocking::{Connection, ConnectionBuilder, MessageIterator},
+  MatchRule, Message,
+};
+
+use crate::{
+  config::Bus,
+  source::{Backoff, DeviceInfo, Push},
+};
+
+/// Turns a signal into a push, or into nothing if it doesn't change anything
+/// elem shows
+///
+/// The connection the signal came in on is handed over too, for reading
+/// whatever the signal leaves out.
+pub type ToPush = fn(&Connection, &Message) -> zbus::Result<Option<Push>>;
+
+fn connect(bus: &Bus) -> zbus::Result<Connection> {
+  match bus {
+    Bus::System => Connection::system(),
+    Bus::Session => Connection::session(),
+    Bus::Address(address) =>
+      ConnectionBuilder::address(address.as_str())?.build(),
+  }
+}
+
+/// A D-Bus service that a battery source reads from, like `UPower` or `BlueZ`
+pub struct Service {
+  /// What the service is called in log messages
+  name: &'static str,
+  bus: Bus,
+  connection: Option<Connection>,
+}
+
+impl Service {
+  pub const fn new(name: &'static str, bus: Bus) -> Self {
+    Self {
+      name,
+      bus,
+      connection: None,
+    }
+  }
+
+  /// Get the bus connection, connecting if there isn't one yet
+  pub fn connection(&mut self) -> zbus::Result<&Connection> {
+    if let Some(ref connection) = self.connection {
+      Ok(connection)
+    } else {
+      debug!("connecting to {} on the {:?} bus", self.name, self.bus);
+
+      Ok(self.connection.insert(connect(&self.bus)?))
+    }
+  }
+
+  pub const fn is_connected(&self) -> bool { self.connection.is_some() }
+
+  /// List devices with `list`, labelling them for the devices menu
+  pub fn devices(
+    &mut self,
+    list: impl FnOnce(&Connection) -> zbus::Result<Vec<DeviceInfo>>,
+  ) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    let devices = self.connection().and_then(list);
+
+    // The service might have gone away, so the next request connects again
+    if devices.is_err() {
+      self.connection = None;
+    }
+
+    Ok(crate::source::label_devices(devices?))
+  }
+
+  /// Forward the signals matching `rule` that `to_push` turns into pushes to
+  /// `sender`, until it has no receiver left
+  ///
+  /// Listening blocks, so it gets its own thread and its own connection, which
+  /// is opened again whenever it's lost.
+  pub fn subscribe(
+    &self,
+    rule: MatchRule<'static>,
+    to_push: ToPush,
+    sender: Sender<Push>,
+  ) {
+    let name = self.name;
+    let bus = self.bus.clone();
+
+    std::thread::spawn(move || {
+      let mut backoff = Backoff::default();
+
+      while let Err(e) = listen(name, &bus, &rule, to_push, &sender) {
+        warn!("lost {} subscription, resubscribing: {}", name, e);
+
+        backoff.wait();
+      }
+    });
+  }
+}
+
+fn listen(
+  name: &str,
+  bus: &Bus,
+  rule: &MatchRule<'static>,
+  to_push: ToPush,
+  sender: &Sender<Push>,
+) -> zbus::Result<()> {
+  let connection = connect(bus)?;
+
+  debug!("listening for {} changes on the {:?} bus", name, bus);
+
+  for message in
+    MessageIterator::for_match_rule(rule.clone(), &connection, None)?
+  {
+    let message = message?;
+    let Some(push) = to_push(&connection, &message)? else {
+      continue;
+    };
+
+    // The receiver is gone, so nobody is listening anymore
+    if sender.send(push).is_err() {
+      return Ok(());
+    }
+  }
+
+  Err(zbus::Error::Failure(format!("connection to {name} closed")))
+}
+
+#[cfg(test)]
+pub mod tests {
+  use std::{
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+    sync::mpsc::Receiver,
+    time::Duration,
+  };
+
+  use super::*;
+
+  /// A `dbus-daemon` of a test's own, to serve stand-in services on, which is
+  /// stopped once dropped
+  pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+  }
+
+  impl PrivateBus {
+    /// Start a bus, which takes `dbus-daemon` being installed
+    pub fn start() -> Self {
+      let mut daemon = Command::new("dbus-daemon")
+        .args(["--session", "--nofork", "--print-address"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("dbus-daemon is needed to test against a private bus");
+      let mut address = String::new();
+
+      BufReader::new(daemon.stdout.take().unwrap())
+        .read_line(&mut address)
+        .unwrap();
+
+      Self {
+        daemon,
+        address: address.trim().to_string(),
+      }
+    }
+
+    pub fn bus(&self) -> Bus { Bus::Address(self.address.clone()) }
+
+    /// Start building a stand-in service, owning `name` on this bus
+    pub fn serve(&self, name: &'static str) -> ConnectionBuilder<'static> {
+      ConnectionBuilder::address(self.address.as_str())
+        .unwrap()
+        .name(name)
+        .unwrap()
+    }
+  }
+
+  impl Drop for PrivateBus {
+    fn drop(&mut self) {
+      self.daemon.kill().ok();
+      self.daemon.wait().ok();
+    }
+  }
+
+  /// Keep running `emit` until `pushes` receives a push that `expected`
+  /// matches, since signals only reach a listener once it has subscribed
+  pub fn push_from(
+    emit: impl Fn(),
+    pushes: &Receiver<Push>,
+    expected: impl Fn(&Push) -> bool,
+  ) -> Push {
+    for _ in 0..50 {
+      emit();
+
+      while let Ok(push) = pushes.recv_timeout(Duration::from_millis(100)) {
+        if expected(&push) {
+          return push;
+        }
+      }
+    }
+
+    panic!("nothing expected was pushed");
+  }
+
+  #[test]
+  fn connects_again_once_listing_fails() {
+    let bus = PrivateBus::start();
+    let mut service = Service::new("nothing", bus.bus());
+
+    // Nothing owns the name, so listing from it fails
+    assert!(service
+      .devices(|connection| {
+        zbus::blocking::Proxy::new(
+          connection,
+          "org.example.Nothing",
+          "/",
+          "org.example.Nothing",
+        )?
+        .call::<_, _, ()>("List", &())?;
+
+        Ok(vec![])
+      })
+      .is_err());
+    assert!(!service.is_connected());
+    assert!(service.devices(|_| Ok(vec![])).unwrap().is_empty());
+    assert!(service.is_connected());
+  }
+}
@@ -0,0 +1,210 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use crate::{
+  ascii_art::{paint, Glyph, Palette, Pixels},
+  config::GaugeConfig,
+};
+
+/// How wide and tall the gauge is, in pixels
+const SIZE: usize = 16;
+/// The battery's outline, from its first to its last column and row
+const BODY_RIGHT: usize = 13;
+const BODY_TOP: usize = 3;
+const BODY_BOTTOM: usize = 12;
+/// The battery's terminal, which sticks out to the right of its outline
+const TERMINAL_TOP: usize = 6;
+const TERMINAL_BOTTOM: usize = 9;
+/// The fill bar, which keeps a pixel of room from the outline
+const FILL_LEFT: usize = 2;
+const FILL_WIDTH: usize = 10;
+const FILL_TOP: usize = 5;
+const FILL_BOTTOM: usize = 10;
+/// The bolt drawn over a charging battery, and where it goes
+const BOLT: [&str; 8] = [
+  "....##", //
+  "...##.", //
+  "..##..", //
+  ".#####", //
+  "#####.", //
+  "..##..", //
+  ".##...", //
+  "##....", //
+];
+const BOLT_LEFT: usize = 4;
+const BOLT_TOP: usize = 4;
+
+/// Draws battery levels as a battery whose fill bar is as full as the battery
+/// is, which is a lot easier to read at a glance than digits at tray icon
+/// sizes
+pub struct Gauge {
+  bolt: bool,
+  /// The color to fill the gauge with at or below a battery level
+  warning: Option<(u8, [u8; 4])>,
+}
+
+impl Gauge {
+  pub fn from_config(config: &GaugeConfig) -> Self {
+    Self {
+      bolt: config.bolt,
+      warning: config.warning.map(|color| (config.warning_below, color.0)),
+    }
+  }
+
+  /// Rasterize a glyph into a PNG, painted with `palette`
+  ///
+  /// Only battery levels are drawn as a gauge, so anything else is drawn as
+  /// its ASCII art.
+  pub fn to_image(&self, glyph: Glyph, palette: &Palette) -> Vec<u8> {
+    self
+      .pixels(glyph, palette)
+      .map_or_else(|| glyph.to_image(palette), |pixels| paint(&pixels, palette))
+  }
+
+  /// Draw a battery level as a gauge, or nothing if the glyph isn't one
+  fn pixels(&self, glyph: Glyph, palette: &Palette) -> Option<Pixels> {
+    let (percentage, charging) = match glyph {
+      Glyph::Percent(percentage) => (percentage, false),
+      Glyph::Charging(percentage) => (percentage, true),
+      Glyph::Loading | Glyph::Error | Glyph::Disconnected => return None,
+    };
+    let outline = palette.status();
+    let fill = match self.warning {
+      Some((below, color)) if percentage <= below => color,
+      _ => palette.foreground(glyph),
+    };
+    // Rounding to the nearest pixel, but never leaving a battery which isn't
+    // completely empty without any fill
+    let filled =
+      match (usize::from(percentage.min(100)) * FILL_WIDTH + 50) / 100 {
+        0 if percentage > 0 => 1,
+        filled => filled,
+      };
+    let mut pixels: Pixels = vec![vec![None; SIZE]; SIZE];
+
+    for (y, row) in pixels.iter_mut().enumerate() {
+      for (x, pixel) in row.iter_mut().enumerate() {
+        let body = (BODY_TOP..=BODY_BOTTOM).contains(&y)
+          && (x == 0 || x == BODY_RIGHT)
+          || x <= BODY_RIGHT && (y == BODY_TOP || y == BODY_BOTTOM);
+        let terminal =
+          x > BODY_RIGHT && (TERMINAL_TOP..=TERMINAL_BOTTOM).contains(&y);
+        let bar = (FILL_TOP..=FILL_BOTTOM).contains(&y)
+          && (FILL_LEFT..FILL_LEFT + filled).contains(&x);
+
+        *pixel = if body || terminal {
+          Some(outline)
+        } else if bar {
+          Some(fill)
+        } else {
+          None
+        };
+      }
+    }
+
+    if charging && self.bolt {
+      let bolt = |x: usize, y: usize| {
+        (BOLT_LEFT..BOLT_LEFT + BOLT[0].len()).contains(&x)
+          && (BOLT_TOP..BOLT_TOP + BOLT.len()).contains(&y)
+          && BOLT[y - BOLT_TOP].as_bytes()[x - BOLT_LEFT] == b'#'
+      };
+
+      // The bolt is drawn in the outline's color with a pixel of room cut out
+      // of the fill bar around it, so that it shows no matter the palette or
+      // battery level
+      for (y, row) in pixels.iter_mut().enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+          if bolt(x, y) {
+            *pixel = Some(outline);
+          } else if (FILL_TOP..=FILL_BOTTOM).contains(&y)
+            && (FILL_LEFT..FILL_LEFT + FILL_WIDTH).contains(&x)
+            && (y - 1..=y + 1).any(|y| (x - 1..=x + 1).any(|x| bolt(x, y)))
+          {
+            *pixel = None;
+          }
+        }
+      }
+    }
+
+    Some(pixels)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::config::IconConfig;
+
+  /// How many pixels of the fill bar are filled
+  fn filled(gauge: &Gauge, percentage: u8) -> usize {
+    let palette = Palette::from_config(&IconConfig::default());
+    let pixels = gauge.pixels(Glyph::Percent(percentage), &palette).unwrap();
+
+    pixels[FILL_TOP]
+      .iter()
+      .skip(FILL_LEFT)
+      .take(FILL_WIDTH)
+      .filter(|pixel| pixel.is_some())
+      .count()
+  }
+
+  #[test]
+  fn fills_as_much_as_the_battery_is_full() {
+    let gauge = Gauge::from_config(&GaugeConfig::default());
+
+    assert_eq!(filled(&gauge, 0), 0);
+    // Never empty unless the battery is
+    assert_eq!(filled(&gauge, 1), 1);
+    assert_eq!(filled(&gauge, 50), FILL_WIDTH / 2);
+    assert_eq!(filled(&gauge, 100), FILL_WIDTH);
+  }
+
+  #[test]
+  fn fills_no_more_than_the_bar() {
+    let gauge = Gauge::from_config(&GaugeConfig::default());
+
+    assert_eq!(filled(&gauge, 101), FILL_WIDTH);
+    assert_eq!(filled(&gauge, u8::MAX), FILL_WIDTH);
+  }
+
+  #[test]
+  fn draws_only_battery_levels() {
+    let gauge = Gauge::from_config(&GaugeConfig::default());
+    let palette = Palette::from_config(&IconConfig::default());
+
+    assert!(gauge.pixels(Glyph::Charging(u8::MAX), &palette).is_some());
+    assert!(gauge.pixels(Glyph::Loading, &palette).is_none());
+    assert!(gauge.pixels(Glyph::Disconnected, &palette).is_none());
+  }
+
+  #[test]
+  fn warns_at_or_below_the_warning_level() {
+    const ORANGE: [u8; 4] = [255, 128, 0, 255];
+    let gauge = Gauge::from_config(&GaugeConfig {
+      warning: Some(crate::config::Color(ORANGE)),
+      ..GaugeConfig::default()
+    });
+    let palette = Palette::from_config(&IconConfig::default());
+    let fill = |percentage| {
+      gauge.pixels(Glyph::Percent(percentage), &palette).unwrap()[FILL_TOP]
+        [FILL_LEFT]
+    };
+
+    assert_eq!(fill(20), Some(ORANGE));
+    assert_eq!(fill(21), Some(palette.foreground(Glyph::Percent(21))));
+  }
+}
@@ -0,0 +1,867 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  sync::mpsc::Sender,
+  time::{Duration, Instant},
+};
+#[cfg(target_os = "linux")]
+use std::{
+  io::{Read, Write},
+  os::unix::io::AsRawFd,
+  path::Path,
+};
+
+use crate::{
+  config::HidppConfig,
+  source::{BatteryState, DeviceInfo, Push},
+};
+
+const SHORT: u8 = 0x10;
+const LONG: u8 = 0x11;
+const LONG_LENGTH: usize = 20;
+/// Tells our replies apart from the ones meant for the kernel driver, or
+/// anything else talking to the device
+const SOFTWARE_ID: u8 = 0x0a;
+const ROOT: u8 = 0x00;
+const ERROR: u8 = 0xff;
+const LEGACY_ERROR: u8 = 0x8f;
+
+const DEVICE_NAME: u16 = 0x0005;
+const BATTERY_STATUS: u16 = 0x1000;
+const UNIFIED_BATTERY: u16 = 0x1004;
+
+/// The device index of a device plugged in directly, instead of through a
+/// receiver
+const DIRECT: u8 = 0xff;
+/// Device indices of everything paired to a receiver
+const PAIRED: std::ops::RangeInclusive<u8> = 1..=6;
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// How long a device index is given to answer while probing, since there's
+/// usually nothing behind most of them, and each is waited out in turn
+const PROBE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Everything that can go wrong while talking HID++ to a device
+#[derive(Debug)]
+pub enum Error {
+  /// The transport failed while reading or writing a report
+  Io(std::io::Error),
+  /// Nothing replied in time, most likely because nothing is paired at the
+  /// device index
+  Timeout,
+  /// The device replied with a HID++ error code
+  Device(u8),
+  /// The device doesn't support any of the battery features
+  Unsupported,
+}
+
+impl std::fmt::Display for Error {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Io(e) => write!(f, "failed to talk to hid++ device: {e}"),
+      Self::Timeout => write!(f, "hid++ device didn't reply in time"),
+      Self::Device(code) =>
+        write!(f, "hid++ device replied with error {code:#04x}"),
+      Self::Unsupported => write!(f, "hid++ device doesn't report its battery"),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Io(e) => Some(e),
+      Self::Timeout | Self::Device(_) | Self::Unsupported => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for Error {
+  fn from(e: std::io::Error) -> Self { Self::Io(e) }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Something HID++ reports can be written to and read from
+///
+/// This is usually a hidraw device, but can just as well be a recording being
+/// replayed, so that the protocol can be exercised without any hardware.
+pub trait Transport: Send {
+  fn write(&mut self, report: &[u8]) -> std::io::Result<()>;
+
+  /// Read a single report, or `None` if nothing arrived within `timeout`
+  fn read(&mut self, timeout: Duration) -> std::io::Result<Option<Vec<u8>>>;
+}
+
+/// A Linux hidraw device, like `/dev/hidraw0`
+#[cfg(target_os = "linux")]
+pub struct Hidraw {
+  file: std::fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl Hidraw {
+  pub fn open(path: &Path) -> std::io::Result<Self> {
+    Ok(Self {
+      file: std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?,
+    })
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl Transport for Hidraw {
+  fn write(&mut self, report: &[u8]) -> std::io::Result<()> {
+    self.file.write_all(report)
+  }
+
+  fn read(&mut self, timeout: Duration) -> std::io::Result<Option<Vec<u8>>> {
+    let mut poll_fd = libc::pollfd {
+      fd: self.file.as_raw_fd(),
+      events: libc::POLLIN,
+      revents: 0,
+    };
+    let ready = unsafe {
+      libc::poll(
+        std::ptr::addr_of_mut!(poll_fd),
+        1,
+        i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX),
+      )
+    };
+
+    match ready {
+      -1 => Err(std::io::Error::last_os_error()),
+      0 => Ok(None),
+      _ => {
+        let mut report = [0; 64];
+        let length = self.file.read(&mut report)?;
+
+        Ok(Some(report[..length].to_vec()))
+      }
+    }
+  }
+}
+
+/// Parse a report written out as space separated hexadecimal bytes
+fn parse_report(line: &str) -> std::io::Result<Vec<u8>> {
+  line
+    .split_whitespace()
+    .map(|byte| {
+      u8::from_str_radix(byte, 16).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+      })
+    })
+    .collect()
+}
+
+fn format_report(report: &[u8]) -> String {
+  report
+    .iter()
+    .map(|byte| format!("{byte:02x}"))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+/// A recorded sequence of reports, played back in order
+///
+/// Recordings are plain text, one report per line. Lines starting with `>` are
+/// reports elem is expected to write, and lines starting with `<` are reports
+/// the device replies with. Blank lines and lines starting with `#` are
+/// skipped.
+///
+/// ```text
+/// # Root.getFeature(UNIFIED_BATTERY)
+/// > 11 ff 00 0a 10 04 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+/// < 11 ff 00 0a 08 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+/// ```
+pub struct Replay {
+  reports: VecDeque<(bool, Vec<u8>)>,
+}
+
+impl Replay {
+  pub fn open(path: &std::path::Path) -> std::io::Result<Self> {
+    Self::parse(&std::fs::read_to_string(path)?)
+  }
+
+  fn parse(recording: &str) -> std::io::Result<Self> {
+    let mut reports = VecDeque::new();
+
+    for line in recording.lines() {
+      let line = line.trim();
+
+      if let Some(report) = line.strip_prefix('>') {
+        reports.push_back((true, parse_report(report)?));
+      } else if let Some(report) = line.strip_prefix('<') {
+        reports.push_back((false, parse_report(report)?));
+      }
+    }
+
+    Ok(Self { reports })
+  }
+}
+
+impl Transport for Replay {
+  fn write(&mut self, report: &[u8]) -> std::io::Result<()> {
+    match self.reports.pop_front() {
+      Some((true, expected)) if expected == report => Ok(()),
+      expected => Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+          "recording expected {}, but got a write of {}",
+          expected.map_or_else(
+            || "nothing".to_string(),
+            |(written, expected)| format!(
+              "a {} of {}",
+              if written { "write" } else { "read" },
+              format_report(&expected)
+            )
+          ),
+          format_report(report)
+        ),
+      )),
+    }
+  }
+
+  /// The recording is never waited on, so a read with nothing to replay times
+  /// out straight away
+  fn read(&mut self, _timeout: Duration) -> std::io::Result<Option<Vec<u8>>> {
+    if matches!(self.reports.front(), Some((false, _))) {
+      Ok(self.reports.pop_front().map(|(_, report)| report))
+    } else {
+      Ok(None)
+    }
+  }
+}
+
+/// Records every report passing through another transport, in the format
+/// `Replay` plays back
+#[cfg(target_os = "linux")]
+pub struct Recorder {
+  transport: Box<dyn Transport>,
+  recording: std::fs::File,
+}
+
+#[cfg(target_os = "linux")]
+impl Recorder {
+  pub fn new(
+    transport: Box<dyn Transport>,
+    path: &std::path::Path,
+  ) -> std::io::Result<Self> {
+    Ok(Self {
+      transport,
+      // Reopening a transport, like after it was unplugged, adds on to what
+      // was recorded before
+      recording: std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?,
+    })
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl Transport for Recorder {
+  fn write(&mut self, report: &[u8]) -> std::io::Result<()> {
+    self.transport.write(report)?;
+    writeln!(self.recording, "> {}", format_report(report))
+  }
+
+  fn read(&mut self, timeout: Duration) -> std::io::Result<Option<Vec<u8>>> {
+    let report = self.transport.read(timeout)?;
+
+    if let Some(ref report) = report {
+      writeln!(self.recording, "< {}", format_report(report))?;
+    }
+
+    Ok(report)
+  }
+}
+
+/// Send a HID++ 2.0 request and wait for its reply, returning the reply's
+/// parameters
+fn request(
+  transport: &mut dyn Transport,
+  device_index: u8,
+  feature_index: u8,
+  function: u8,
+  parameters: &[u8],
+  timeout: Duration,
+) -> Result<[u8; 16]> {
+  let address = function << 4 | SOFTWARE_ID;
+  let mut report = vec![LONG, device_index, feature_index, address];
+  let deadline = Instant::now() + timeout;
+
+  report.extend_from_slice(parameters);
+  report.resize(LONG_LENGTH, 0);
+  transport.write(&report)?;
+
+  loop {
+    let Some(reply) =
+      transport.read(deadline.saturating_duration_since(Instant::now()))?
+    else {
+      return Err(Error::Timeout);
+    };
+
+    // Anything else, like notifications or replies meant for someone else, is
+    // skipped.
+    if reply.len() < 6 || reply[1] != device_index {
+      continue;
+    }
+
+    let is_error = (reply[2] == ERROR && reply[0] == LONG
+      || reply[2] == LEGACY_ERROR && reply[0] == SHORT)
+      && reply[3] == feature_index
+      && reply[4] == address;
+
+    if is_error {
+      return Err(Error::Device(reply[5]));
+    }
+
+    if reply[2] == feature_index && reply[3] == address {
+      let mut reply_parameters = [0; 16];
+      let length = (reply.len() - 4).min(16);
+
+      reply_parameters[..length].copy_from_slice(&reply[4..4 + length]);
+
+      return Ok(reply_parameters);
+    }
+  }
+}
+
+/// Look up the index of a feature, if the device has it
+fn feature(
+  transport: &mut dyn Transport,
+  device_index: u8,
+  feature_id: u16,
+  timeout: Duration,
+) -> Result<Option<u8>> {
+  let [high, low] = feature_id.to_be_bytes();
+  let index =
+    request(transport, device_index, ROOT, 0, &[high, low], timeout)?[0];
+
+  Ok((index != 0).then_some(index))
+}
+
+/// How a device reports its battery
+#[derive(Clone, Copy)]
+enum BatteryFeature {
+  Unified(u8),
+  Status(u8),
+}
+
+impl BatteryFeature {
+  /// Ask a device index how it reports its battery, giving up quickly if
+  /// nothing answers
+  fn find(transport: &mut dyn Transport, device_index: u8) -> Result<Self> {
+    if let Some(index) =
+      feature(transport, device_index, UNIFIED_BATTERY, PROBE_TIMEOUT)?
+    {
+      return Ok(Self::Unified(index));
+    }
+
+    // Having answered, the device is given as long as any other request
+    feature(transport, device_index, BATTERY_STATUS, TIMEOUT)?
+      .map(Self::Status)
+      .ok_or(Error::Unsupported)
+  }
+
+  fn read(
+    self,
+    transport: &mut dyn Transport,
+    device_index: u8,
+  ) -> Result<BatteryState> {
+    Ok(match self {
+      Self::Unified(index) => {
+        let status = request(transport, device_index, index, 1, &[], TIMEOUT)?;
+        let levels = status[1];
+        // Some devices only report a level, not a percentage, so the level is
+        // used as a rough estimate for those.
+        let percentage = match status[0] {
+          0 if levels & 0b1000 != 0 => 100,
+          0 if levels & 0b0100 != 0 => 50,
+          0 if levels & 0b0010 != 0 => 20,
+          0 if levels & 0b0001 != 0 => 5,
+          percentage => percentage,
+        };
+
+        BatteryState::new(
+          u64::from(percentage),
+          matches!(status[2], 1 | 2),
+          None,
+          levels & 0b0001 != 0,
+          levels & 0b0010 != 0,
+        )
+      }
+      Self::Status(index) => {
+        let status = request(transport, device_index, index, 0, &[], TIMEOUT)?;
+
+        BatteryState::new(
+          u64::from(status[0]),
+          matches!(status[2], 1 | 2 | 4),
+          None,
+          false,
+          false,
+        )
+      }
+    })
+  }
+}
+
+/// Read a device's name, if it has the feature for it
+fn device_name(
+  transport: &mut dyn Transport,
+  device_index: u8,
+) -> Result<Option<String>> {
+  let Some(index) = feature(transport, device_index, DEVICE_NAME, TIMEOUT)?
+  else {
+    return Ok(None);
+  };
+  let length =
+    usize::from(request(transport, device_index, index, 0, &[], TIMEOUT)?[0]);
+  let mut name = vec![];
+
+  while name.len() < length {
+    // The name is always shorter than 256 characters because its length is
+    // a single byte.
+    #[allow(clippy::cast_possible_truncation)]
+    let chunk = request(
+      transport,
+      device_index,
+      index,
+      1,
+      &[name.len() as u8],
+      TIMEOUT,
+    )?;
+
+    name.extend_from_slice(&chunk[..(length - name.len()).min(16)]);
+  }
+
+  Ok(Some(String::from_utf8_lossy(&name).to_string()))
+}
+
+/// A device found on a transport, and where its battery is read from
+struct Found {
+  transport: String,
+  device_index: u8,
+  battery_feature: BatteryFeature,
+  display_name: String,
+}
+
+/// Logitech devices read directly over HID++ 2.0, without G HUB
+///
+/// Devices are found on every Logitech hidraw device, both plugged in directly
+/// and paired to a receiver. Each device's ID is its transport's name and
+/// device index, like `hidraw3:01`.
+///
+/// Looking for devices means asking every device index on a transport, waiting
+/// out the ones with nothing behind them, so each transport is only probed once
+/// it's opened. Devices paired to a receiver get a hidraw device of their own
+/// once they connect, so newly turned on devices still turn up.
+pub struct HidPlusPlus {
+  config: HidppConfig,
+  transports: HashMap<String, Box<dyn Transport>>,
+  /// Every device found on the open transports, keyed by ID
+  found: HashMap<String, Found>,
+  /// Transports which don't take HID++ reports, like a receiver's keyboard
+  /// interface, which aren't opened again unless they're plugged in again
+  not_hidpp: HashSet<String>,
+}
+
+impl HidPlusPlus {
+  pub fn new(config: &HidppConfig) -> Self {
+    Self {
+      config: config.clone(),
+      transports: HashMap::new(),
+      found: HashMap::new(),
+      not_hidpp: HashSet::new(),
+    }
+  }
+
+  /// Open any transports which aren't open yet, and close the ones which have
+  /// gone away, returning the names of the newly opened ones
+  ///
+  /// Transports are looked for again every time, so that receivers plugged in
+  /// later, and recordings closed after running out, are picked up.
+  fn open(&mut self) -> std::io::Result<Vec<String>> {
+    let mut opened = vec![];
+
+    if let Some(ref replay) = self.config.replay {
+      for entry in std::fs::read_dir(replay)? {
+        let path = entry?.path();
+        let name = path
+          .file_stem()
+          .unwrap_or_default()
+          .to_string_lossy()
+          .to_string();
+
+        if self.transports.contains_key(&name) || self.not_hidpp.contains(&name)
+        {
+          continue;
+        }
+
+        debug!("replaying hid++ recording {:?}", path);
+        self
+          .transports
+          .insert(name.clone(), Box::new(Replay::open(&path)?));
+        opened.push(name);
+      }
+
+      return Ok(opened);
+    }
+
+    #[cfg(target_os = "linux")]
+    let names = logitech_hidraw_devices()?;
+
+    #[cfg(target_os = "linux")]
+    {
+      self.transports.retain(|name, _| {
+        let plugged_in = names.contains(name);
+
+        if !plugged_in {
+          debug!("{} was unplugged", name);
+        }
+
+        plugged_in
+      });
+      self
+        .found
+        .retain(|_, found| self.transports.contains_key(&found.transport));
+      self.not_hidpp.retain(|name| names.contains(name));
+    }
+
+    #[cfg(target_os = "linux")]
+    for name in names {
+      if self.transports.contains_key(&name) || self.not_hidpp.contains(&name) {
+        continue;
+      }
+
+      let transport = match Hidraw::open(&Path::new("/dev").join(&name)) {
+        Ok(transport) => transport,
+        Err(e) => {
+          debug!("skipping {}: {}", name, e);
+
+          continue;
+        }
+      };
+      let transport: Box<dyn Transport> = match self.config.record {
+        Some(ref record) => Box::new(Recorder::new(
+          Box::new(transport),
+          &record.join(format!("{name}.hidpp")),
+        )?),
+        None => Box::new(transport),
+      };
+
+      debug!("opened {}", name);
+      self.transports.insert(name.clone(), transport);
+      opened.push(name);
+    }
+
+    Ok(opened)
+  }
+
+  /// Close a transport, forgetting every device found on it
+  fn close(&mut self, name: &str) {
+    self.transports.remove(name);
+    self.found.retain(|_, found| found.transport != name);
+  }
+
+  /// Look for devices on every device index of a transport
+  fn probe(&mut self, name: &str) {
+    let Some(transport) = self.transports.get_mut(name) else {
+      return;
+    };
+
+    for device_index in std::iter::once(DIRECT).chain(PAIRED) {
+      let battery_feature =
+        match BatteryFeature::find(transport.as_mut(), device_index) {
+          Ok(battery_feature) => battery_feature,
+          // Either the hidraw device was unplugged, so it's opened and probed
+          // again if it comes back, or it doesn't take HID++ reports at all,
+          // so it's left alone until then
+          Err(Error::Io(e)) => {
+            debug!("{} doesn't speak hid++: {}", name, e);
+            self.close(name);
+            self.not_hidpp.insert(name.to_string());
+
+            return;
+          }
+          Err(_) => continue,
+        };
+      let id = format!("{name}:{device_index:02x}");
+      let display_name = device_name(transport.as_mut(), device_index)
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| format!("Logitech Device ({id})"));
+
+      debug!("found {} on {}", display_name, id);
+      self.found.insert(
+        id,
+        Found {
+          transport: name.to_string(),
+          device_index,
+          battery_feature,
+          display_name,
+        },
+      );
+    }
+  }
+}
+
+/// The names of every hidraw device made by Logitech
+#[cfg(target_os = "linux")]
+fn logitech_hidraw_devices() -> std::io::Result<Vec<String>> {
+  let mut names = vec![];
+
+  for entry in std::fs::read_dir("/sys/class/hidraw")? {
+    let entry = entry?;
+    let uevent = std::fs::read_to_string(entry.path().join("device/uevent"))
+      .unwrap_or_default();
+
+    // Logitech's USB vendor ID
+    if uevent
+      .lines()
+      .any(|line| line.starts_with("HID_ID=") && line.contains(":0000046D:"))
+    {
+      names.push(entry.file_name().to_string_lossy().to_string());
+    }
+  }
+
+  Ok(names)
+}
+
+impl crate::source::BatterySource for HidPlusPlus {
+  fn name(&self) -> &'static str { "hid++" }
+
+  fn is_connected(&self) -> bool { true }
+
+  fn devices(&mut self) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    for name in self.open()? {
+      self.probe(&name);
+    }
+
+    Ok(crate::source::label_devices(
+      self
+        .found
+        .iter()
+        .map(|(id, found)| {
+          DeviceInfo::new(id, "WIRELESS", "UNKNOWN", &found.display_name)
+        })
+        .collect(),
+    ))
+  }
+
+  fn battery(&mut self, id: &str) -> crate::source::Result<BatteryState> {
+    if !self.found.contains_key(id) {
+      self.devices()?;
+    }
+
+    let Some(found) = self.found.get(id) else {
+      return Err(format!("no hid++ device with id '{id}'").into());
+    };
+    let name = found.transport.clone();
+    let (device_index, battery_feature) =
+      (found.device_index, found.battery_feature);
+    let transport = self
+      .transports
+      .get_mut(&name)
+      .ok_or_else(|| format!("'{name}' is gone"))?;
+
+    match battery_feature.read(transport.as_mut(), device_index) {
+      // The hidraw device was unplugged, so it's opened and probed again if it
+      // comes back
+      Err(Error::Io(e)) => {
+        debug!("lost {}: {}", name, e);
+        self.close(&name);
+
+        Err(Error::Io(e).into())
+      }
+      battery_state => Ok(battery_state?),
+    }
+  }
+
+  /// Battery notifications aren't listened for, so HID++ devices are only
+  /// ever polled
+  fn subscribe(&mut self, _sender: Sender<Push>) {}
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::*;
+  use crate::source::BatterySource;
+
+  /// A directory of recordings to replay, removed again once dropped
+  struct Recordings(PathBuf);
+
+  impl Recordings {
+    /// Lay out `recordings` as hidraw devices named after their keys
+    fn new(test: &str, recordings: &[(&str, &str)]) -> Self {
+      let root = std::env::temp_dir()
+        .join(format!("elem-hidpp-{}-{test}", std::process::id()));
+
+      std::fs::remove_dir_all(&root).ok();
+      std::fs::create_dir_all(&root).unwrap();
+
+      for (name, recording) in recordings {
+        std::fs::write(root.join(format!("{name}.hidpp")), recording).unwrap();
+      }
+
+      Self(root)
+    }
+
+    fn source(&self) -> HidPlusPlus {
+      HidPlusPlus::new(&HidppConfig {
+        replay: Some(self.0.clone()),
+        record: None,
+      })
+    }
+  }
+
+  impl Drop for Recordings {
+    fn drop(&mut self) { std::fs::remove_dir_all(&self.0).ok(); }
+  }
+
+  const UNIFIED: &str = include_str!("../fixtures/hidpp/unified_battery.hidpp");
+  const STATUS: &str = include_str!("../fixtures/hidpp/battery_status.hidpp");
+  const DEVICE_ERROR: &str =
+    include_str!("../fixtures/hidpp/device_error.hidpp");
+  const TIMEOUT: &str = include_str!("../fixtures/hidpp/timeout.hidpp");
+
+  #[test]
+  fn reads_unified_battery() {
+    let recordings = Recordings::new("unified", &[("receiver", UNIFIED)]);
+    let mut hidpp = recordings.source();
+    let devices = hidpp.devices().unwrap();
+
+    assert_eq!(devices.keys().collect::<Vec<_>>(), ["receiver:01"]);
+    assert_eq!(devices["receiver:01"].label, "G305");
+
+    let state = hidpp.battery("receiver:01").unwrap();
+
+    assert_eq!(state.percentage(), 57);
+    assert!(state.is_charging());
+    assert!(!state.is_low() && !state.is_critical());
+  }
+
+  #[test]
+  fn reads_battery_status() {
+    let recordings = Recordings::new("status", &[("mouse", STATUS)]);
+    let mut hidpp = recordings.source();
+    let devices = hidpp.devices().unwrap();
+
+    assert_eq!(devices.keys().collect::<Vec<_>>(), ["mouse:ff"]);
+    // Without the feature for it, there's no name to go by
+    assert_eq!(devices["mouse:ff"].label, "Logitech Device (mouse:ff)");
+
+    let state = hidpp.battery("mouse:ff").unwrap();
+
+    assert_eq!(state.percentage(), 50);
+    assert!(!state.is_charging());
+  }
+
+  #[test]
+  fn fails_with_the_device_error() {
+    let recordings = Recordings::new("error", &[("receiver", DEVICE_ERROR)]);
+    let mut hidpp = recordings.source();
+    let error = hidpp.battery("receiver:01").unwrap_err();
+
+    assert!(matches!(error.downcast_ref(), Some(Error::Device(0x08))));
+  }
+
+  #[test]
+  fn times_out_without_a_reply() {
+    let recordings = Recordings::new("timeout", &[("receiver", TIMEOUT)]);
+    let mut hidpp = recordings.source();
+    let error = hidpp.battery("receiver:01").unwrap_err();
+
+    assert!(matches!(error.downcast_ref(), Some(Error::Timeout)));
+  }
+
+  #[test]
+  fn only_probes_newly_opened_transports() {
+    let recordings = Recordings::new("probes", &[("receiver", UNIFIED)]);
+    let mut hidpp = recordings.source();
+
+    hidpp.devices().unwrap();
+
+    // Probing again would write reports the recording doesn't expect, losing
+    // the receiver
+    assert!(hidpp.devices().unwrap().contains_key("receiver:01"));
+    assert_eq!(hidpp.battery("receiver:01").unwrap().percentage(), 57);
+  }
+
+  #[test]
+  fn probes_again_once_a_transport_fails() {
+    let recordings = Recordings::new("fails", &[("receiver", UNIFIED)]);
+    let mut hidpp = recordings.source();
+
+    hidpp.battery("receiver:01").unwrap();
+
+    // The recording has run out, which is as good as being unplugged
+    assert!(matches!(
+      hidpp.battery("receiver:01").unwrap_err().downcast_ref(),
+      Some(Error::Io(_))
+    ));
+    assert!(hidpp.found.is_empty());
+    // Plugged back in, the recording is opened and probed from the start
+    assert_eq!(hidpp.battery("receiver:01").unwrap().percentage(), 57);
+  }
+
+  #[test]
+  fn leaves_transports_without_hidpp_alone() {
+    // Nothing is expected to be written to the keyboard, like an interface
+    // which refuses HID++ reports
+    let recordings =
+      Recordings::new("not_hidpp", &[("receiver", UNIFIED), ("keyboard", "")]);
+    let mut hidpp = recordings.source();
+
+    assert_eq!(
+      hidpp.devices().unwrap().keys().collect::<Vec<_>>(),
+      ["receiver:01"]
+    );
+    hidpp.devices().unwrap();
+    assert!(hidpp.not_hidpp.contains("keyboard"));
+    assert!(!hidpp.transports.contains_key("keyboard"));
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn records_on_after_reopening() {
+    let recordings = Recordings::new("record", &[]);
+    let path = recordings.0.join("receiver.hidpp");
+    let report = parse_report(
+      "11 ff 00 0a 10 04 00 00 00 00 00 00 00 00 00 00 00 00 00 00",
+    )
+    .unwrap();
+
+    for _ in 0..2 {
+      let replay =
+        Replay::parse(&format!("> {}", format_report(&report))).unwrap();
+
+      Recorder::new(Box::new(replay), &path)
+        .unwrap()
+        .write(&report)
+        .unwrap();
+    }
+
+    assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+  }
+}
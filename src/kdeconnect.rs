@@ -0,0 +1,441 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{collections::HashMap, sync::mpsc::Sender};
+
+use zbus::{
+  blocking::{Connection, Proxy},
+  MatchRule, Message, MessageType,
+};
+
+use crate::{
+  config::Bus,
+  dbus::Service,
+  source::{BatteryState, BatteryStateChange, DeviceInfo, Push},
+};
+
+const SERVICE: &str = "org.kde.kdeconnect";
+const PATH: &str = "/modules/kdeconnect";
+const DAEMON_INTERFACE: &str = "org.kde.kdeconnect.daemon";
+const DEVICE_INTERFACE: &str = "org.kde.kdeconnect.device";
+const BATTERY_INTERFACE: &str = "org.kde.kdeconnect.device.battery";
+const BATTERY_PLUGIN: &str = "kdeconnect_battery";
+
+// KDE Connect only reports a charge, so these are Android's own low and
+// critical battery warning levels.
+const LOW: i32 = 15;
+const CRITICAL: i32 = 5;
+
+/// The G HUB-style name of a KDE Connect device type
+fn device_type(kind: &str) -> &'static str {
+  match kind {
+    "phone" | "smartphone" => "PHONE",
+    "tablet" => "TABLET",
+    _ => "UNKNOWN",
+  }
+}
+
+fn device_path(id: &str) -> String { format!("{PATH}/devices/{id}") }
+
+/// Phones and tablets paired with KDE Connect, usually on the session bus
+///
+/// Each device's KDE Connect ID is used as its device ID.
+pub struct KdeConnect {
+  service: Service,
+}
+
+impl KdeConnect {
+  pub const fn new(bus: Bus) -> Self {
+    Self {
+      service: Service::new("kde connect", bus),
+    }
+  }
+
+  fn device<'a>(
+    connection: &'a Connection,
+    id: &str,
+  ) -> zbus::Result<Proxy<'a>> {
+    Proxy::new(connection, SERVICE, device_path(id), DEVICE_INTERFACE)
+  }
+
+  /// Whether a device can be reached and shares its battery
+  fn has_battery(device: &Proxy<'_>) -> zbus::Result<bool> {
+    Ok(
+      device.get_property::<bool>("isReachable")?
+        && device.call::<_, _, bool>("hasPlugin", &(BATTERY_PLUGIN,))?,
+    )
+  }
+
+  fn battery_state(
+    connection: &Connection,
+    id: &str,
+  ) -> zbus::Result<BatteryState> {
+    let battery = Proxy::new(
+      connection,
+      SERVICE,
+      format!("{}/battery", device_path(id)),
+      BATTERY_INTERFACE,
+    )?;
+
+    let charge = battery.get_property::<i32>("charge")?;
+
+    // KDE Connect reports -1 for devices which haven't sent their battery yet
+    if charge < 0 {
+      return Err(zbus::Error::Failure(format!(
+        "'{id}' hasn't sent its battery yet"
+      )));
+    }
+
+    Ok(Self::to_battery_state(
+      charge,
+      battery.get_property::<bool>("isCharging")?,
+    ))
+  }
+
+  fn to_battery_state(charge: i32, charging: bool) -> BatteryState {
+    BatteryState::new(
+      u64::try_from(charge).unwrap_or_default(),
+      charging,
+      None,
+      !charging && charge <= CRITICAL,
+      !charging && charge <= LOW,
+    )
+  }
+
+  /// List every reachable, paired device which shares its battery
+  fn list(connection: &Connection) -> zbus::Result<Vec<DeviceInfo>> {
+    let ids = Proxy::new(connection, SERVICE, PATH, DAEMON_INTERFACE)?
+      .call::<_, _, Vec<String>>("devices", &(true, true))?;
+    let mut devices = vec![];
+
+    for id in ids {
+      // A device going away while it's being read shouldn't hide every other
+      // device
+      match Self::battery_device(connection, &id) {
+        Ok(Some(device_info)) => devices.push(device_info),
+        Ok(None) => {}
+        Err(e) => warn!("skipping unreadable kde connect device {}: {}", id, e),
+      }
+    }
+
+    Ok(devices)
+  }
+
+  /// Describe a device, or nothing if it doesn't share its battery
+  fn battery_device(
+    connection: &Connection,
+    id: &str,
+  ) -> zbus::Result<Option<DeviceInfo>> {
+    let device = Self::device(connection, id)?;
+
+    if !Self::has_battery(&device)? {
+      return Ok(None);
+    }
+
+    Ok(Some(DeviceInfo::new(
+      id,
+      "WIRELESS",
+      device_type(&device.get_property::<String>("type")?),
+      &device.get_property::<String>("name")?,
+    )))
+  }
+
+  /// Turn devices coming and going and battery changes into pushes
+  fn to_push(
+    _connection: &Connection,
+    message: &Message,
+  ) -> zbus::Result<Option<Push>> {
+    match message.member().as_deref() {
+      Some(
+        "deviceAdded"
+        | "deviceRemoved"
+        | "deviceVisibilityChanged"
+        | "deviceListChanged"
+        | "reachableChanged"
+        | "pluginsChanged",
+      ) => Ok(Some(Push::DeviceState)),
+      Some("refreshed") => {
+        let Some(id) = message.path().and_then(|path| {
+          path
+            .as_str()
+            .strip_prefix(&format!("{PATH}/devices/"))?
+            .strip_suffix("/battery")
+            .map(ToString::to_string)
+        }) else {
+          return Ok(None);
+        };
+        let (charging, charge) = message.body::<(bool, i32)>()?;
+
+        if charge < 0 {
+          return Ok(None);
+        }
+
+        trace!("received battery state change for '{}'", id);
+
+        Ok(Some(Push::BatteryState(BatteryStateChange::new(
+          &id,
+          Self::to_battery_state(charge, charging),
+        ))))
+      }
+      _ => Ok(None),
+    }
+  }
+}
+
+impl crate::source::BatterySource for KdeConnect {
+  fn name(&self) -> &'static str { "kde connect" }
+
+  fn is_connected(&self) -> bool { self.service.is_connected() }
+
+  fn devices(&mut self) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    self.service.devices(Self::list)
+  }
+
+  fn battery(&mut self, id: &str) -> crate::source::Result<BatteryState> {
+    let connection = self.service.connection()?;
+
+    if !Self::has_battery(&Self::device(connection, id)?)? {
+      return Err(format!("'{id}' isn't sharing its battery").into());
+    }
+
+    Ok(Self::battery_state(connection, id)?)
+  }
+
+  fn subscribe(&mut self, sender: Sender<Push>) {
+    // This will never fail because `SERVICE` is a valid bus name.
+    let rule = MatchRule::builder()
+      .msg_type(MessageType::Signal)
+      .sender(SERVICE)
+      .unwrap()
+      .build();
+
+    self.service.subscribe(rule, Self::to_push, sender);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc;
+
+  use zbus::{dbus_interface, SignalContext};
+
+  use super::*;
+  use crate::{
+    dbus::tests::{push_from, PrivateBus},
+    source::BatterySource,
+  };
+
+  /// A stand-in for the KDE Connect daemon, with every device it knows about
+  struct Daemon {
+    ids: Vec<&'static str>,
+  }
+
+  #[dbus_interface(name = "org.kde.kdeconnect.daemon")]
+  impl Daemon {
+    #[dbus_interface(name = "devices")]
+    fn devices(&self, only_reachable: bool, only_paired: bool) -> Vec<String> {
+      // Every device is listed no matter what's asked for, so that it's elem's
+      // own checks which leave devices out
+      let _ = (only_reachable, only_paired);
+
+      self.ids.iter().map(ToString::to_string).collect()
+    }
+  }
+
+  /// A stand-in for a paired device
+  struct Device {
+    name: &'static str,
+    kind: &'static str,
+    reachable: bool,
+    shares_battery: bool,
+  }
+
+  #[dbus_interface(name = "org.kde.kdeconnect.device")]
+  impl Device {
+    #[dbus_interface(property, name = "name")]
+    fn name(&self) -> String { self.name.to_string() }
+
+    #[dbus_interface(property, name = "type")]
+    fn kind(&self) -> String { self.kind.to_string() }
+
+    #[dbus_interface(property, name = "isReachable")]
+    fn reachable(&self) -> bool { self.reachable }
+
+    #[dbus_interface(name = "hasPlugin")]
+    fn has_plugin(&self, name: &str) -> bool {
+      self.shares_battery && name == BATTERY_PLUGIN
+    }
+  }
+
+  /// A stand-in for a device's battery plugin
+  struct Battery {
+    charge: i32,
+    charging: bool,
+  }
+
+  #[dbus_interface(name = "org.kde.kdeconnect.device.battery")]
+  impl Battery {
+    #[dbus_interface(property, name = "charge")]
+    fn charge(&self) -> i32 { self.charge }
+
+    #[dbus_interface(property, name = "isCharging")]
+    fn charging(&self) -> bool { self.charging }
+
+    #[dbus_interface(signal, name = "refreshed")]
+    async fn refreshed(
+      context: &SignalContext<'_>,
+      charging: bool,
+      charge: i32,
+    ) -> zbus::Result<()>;
+  }
+
+  /// Serve a phone sharing its battery, a phone which hasn't sent its battery
+  /// yet, a tablet that can't be reached, and a laptop not sharing its battery,
+  /// listing a watch which isn't served at all
+  fn serve(bus: &PrivateBus) -> Connection {
+    let mut builder = bus
+      .serve(SERVICE)
+      .serve_at(
+        PATH,
+        Daemon {
+          ids: vec!["phone", "new_phone", "tablet", "watch", "laptop"],
+        },
+      )
+      .unwrap();
+
+    for (id, device, battery) in [
+      (
+        "phone",
+        Device {
+          name: "Pixel 7",
+          kind: "smartphone",
+          reachable: true,
+          shares_battery: true,
+        },
+        Battery {
+          charge: 12,
+          charging: false,
+        },
+      ),
+      (
+        "new_phone",
+        Device {
+          name: "Pixel 8",
+          kind: "phone",
+          reachable: true,
+          shares_battery: true,
+        },
+        Battery {
+          charge: -1,
+          charging: false,
+        },
+      ),
+      (
+        "tablet",
+        Device {
+          name: "Galaxy Tab",
+          kind: "tablet",
+          reachable: false,
+          shares_battery: true,
+        },
+        Battery {
+          charge: 50,
+          charging: false,
+        },
+      ),
+      (
+        "laptop",
+        Device {
+          name: "ThinkPad",
+          kind: "laptop",
+          reachable: true,
+          shares_battery: false,
+        },
+        Battery {
+          charge: 50,
+          charging: false,
+        },
+      ),
+    ] {
+      builder = builder
+        .serve_at(device_path(id), device)
+        .unwrap()
+        .serve_at(format!("{}/battery", device_path(id)), battery)
+        .unwrap();
+    }
+
+    builder.build().unwrap()
+  }
+
+  #[test]
+  fn lists_only_reachable_devices_sharing_their_battery() {
+    let bus = PrivateBus::start();
+    let _kdeconnect = serve(&bus);
+    let devices = KdeConnect::new(bus.bus()).devices().unwrap();
+    let mut ids = devices.keys().map(String::as_str).collect::<Vec<_>>();
+
+    ids.sort_unstable();
+
+    assert_eq!(ids, ["new_phone", "phone"]);
+    assert_eq!(devices["phone"].label, "Pixel 7");
+    assert_eq!(devices["phone"].device_type(), "PHONE");
+  }
+
+  #[test]
+  fn reads_the_charge() {
+    let bus = PrivateBus::start();
+    let _kdeconnect = serve(&bus);
+    let mut kdeconnect = KdeConnect::new(bus.bus());
+    let state = kdeconnect.battery("phone").unwrap();
+
+    assert_eq!(state.percentage(), 12);
+    assert!(!state.is_charging());
+    assert!(state.is_low() && !state.is_critical());
+    assert!(kdeconnect.battery("new_phone").is_err());
+    assert!(kdeconnect.battery("tablet").is_err());
+    assert!(kdeconnect.battery("laptop").is_err());
+  }
+
+  #[test]
+  fn pushes_battery_changes() {
+    let bus = PrivateBus::start();
+    let connection = serve(&bus);
+    let (sender, pushes) = mpsc::channel();
+    let battery = connection
+      .object_server()
+      .interface::<_, Battery>(format!("{}/battery", device_path("phone")))
+      .unwrap();
+
+    KdeConnect::new(bus.bus()).subscribe(sender);
+    push_from(
+      || {
+        zbus::block_on(Battery::refreshed(battery.signal_context(), true, 13))
+          .unwrap();
+      },
+      &pushes,
+      |push| {
+        matches!(
+          push,
+          Push::BatteryState(change)
+            if change.device_id() == "phone"
+              && change.state().percentage() == 13
+              && change.state().is_charging()
+        )
+      },
+    );
+  }
+}
@@ -15,190 +15,927 @@
 // Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
 // SPDX-License-Identifier: GPL-3.0-only
 
-use std::collections::HashMap;
+use std::{
+  collections::{HashMap, HashSet, VecDeque},
+  net::TcpStream,
+  sync::{mpsc::Sender, Arc},
+  time::{Duration, Instant},
+};
 
 use serde_derive::{Deserialize, Serialize};
-use tungstenite::{client::IntoClientRequest, Message};
+use tungstenite::{client::IntoClientRequest, HandshakeError, Message};
 
-use crate::tray::quit;
+use crate::{
+  config::LogitechConfig,
+  source::{
+    Backoff, BatteryState, BatteryStateChange, DeviceInfo, InFlight,
+    Interrupted, Push,
+  },
+};
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct DeviceInfo {
-  pub id: String,
-  #[serde(rename = "connectionType")]
-  connection_type: String,
-  #[serde(rename = "deviceType")]
-  device_type: String,
-  #[serde(rename = "displayName")]
-  pub display_name: String,
-}
-
-impl DeviceInfo {
-  pub fn new(
-    id: &str,
-    connection_type: &str,
-    device_type: &str,
-    display_name: &str,
-  ) -> Self {
-    Self {
-      id: id.to_string(),
-      connection_type: connection_type.to_string(),
-      device_type: device_type.to_string(),
-      display_name: display_name.to_string(),
+struct DeviceList {
+  #[serde(rename = "deviceInfos")]
+  device_infos: Vec<DeviceInfo>,
+}
+
+/// What a request asks G HUB to do with its path
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "UPPERCASE")]
+enum Verb {
+  Get,
+  Subscribe,
+}
+
+/// A request to the Logitech G HUB `WebSocket`
+///
+/// G HUB echoes the message ID back in its reply, which is how replies are
+/// told apart from pushes and from each other.
+#[derive(Serialize, Debug)]
+struct Request<'a> {
+  #[serde(rename = "msgId")]
+  msg_id: String,
+  verb: Verb,
+  path: &'a str,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  payload: Option<serde_json::Value>,
+}
+
+/// Whether G HUB could do what a request asked for
+#[derive(Serialize, Deserialize, Debug)]
+struct ResponseResult {
+  code: String,
+  #[serde(default)]
+  what: String,
+}
+
+/// Anything the Logitech G HUB `WebSocket` sends, be it a reply to a request
+/// or a push
+///
+/// Pushes have an empty message ID, and replies echo the ID of their request.
+#[derive(Serialize, Deserialize, Debug)]
+struct Response {
+  #[serde(rename = "msgId", default)]
+  msg_id: String,
+  #[serde(default)]
+  verb: String,
+  #[serde(default)]
+  path: String,
+  #[serde(default)]
+  payload: serde_json::Value,
+  result: Option<ResponseResult>,
+}
+
+impl Response {
+  /// Turn a non-successful result into an error
+  ///
+  /// Replies without a result are taken at face value.
+  fn into_success(self) -> Result<Self> {
+    match self.result {
+      Some(ref result) if result.code != "SUCCESS" => Err(Error::Result {
+        code: result.code.clone(),
+        what: result.what.clone(),
+      }),
+      _ => Ok(self),
     }
   }
+}
 
-  pub fn from_device_info(device_info: &Self) -> Self {
-    Self {
-      id: device_info.id.clone(),
-      connection_type: device_info.connection_type.clone(),
-      device_type: device_info.device_type.clone(),
-      display_name: device_info.display_name.clone(),
+const BATTERY_STATE_CHANGED: &str = "/battery/state/changed";
+const DEVICE_STATE_CHANGED: &str = "/devices/state/changed";
+
+/// Everything that can go wrong while talking to the Logitech G HUB
+/// `WebSocket`
+#[derive(Debug)]
+pub enum Error {
+  /// The configured G HUB URL isn't a `ws://` URL with a host
+  InvalidUrl(String),
+  /// The `WebSocket` couldn't be opened, most likely because G HUB isn't
+  /// running
+  Connect(Box<tungstenite::Error>),
+  /// The `WebSocket` failed while reading or writing a message
+  Protocol(Box<tungstenite::Error>),
+  /// No wireless device has the requested ID
+  UnknownDevice(String),
+  /// G HUB replied with something that doesn't look like what was asked for
+  MalformedPayload(serde_json::Error),
+  /// G HUB understood the request, but replied with a non-successful result
+  Result { code: String, what: String },
+  /// G HUB didn't reply in time, or the request was cancelled before it did
+  Interrupted(Interrupted),
+}
+
+impl std::fmt::Display for Error {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::InvalidUrl(e) => write!(f, "invalid logitech g hub url: {e}"),
+      Self::Connect(e) => write!(
+        f,
+        "failed to connect to the logitech g hub websocket. is it running? \
+         ({e})"
+      ),
+      Self::Protocol(e) => write!(
+        f,
+        "failed to communicate with the logitech g hub websocket: {e}"
+      ),
+      Self::UnknownDevice(id) => write!(f, "no wireless device with id '{id}'"),
+      Self::MalformedPayload(e) => write!(
+        f,
+        "malformed payload from the logitech g hub websocket: {e}"
+      ),
+      Self::Result { code, what } =>
+        write!(f, "logitech g hub replied with {code}: {what}"),
+      Self::Interrupted(Interrupted::Timeout) =>
+        write!(f, "logitech g hub didn't reply in time"),
+      Self::Interrupted(Interrupted::Cancelled) =>
+        write!(f, "request to logitech g hub was cancelled"),
     }
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct DeviceListPayload {
-  #[serde(rename = "deviceInfos")]
-  device_infos: Vec<DeviceInfo>,
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Self::Connect(e) | Self::Protocol(e) => Some(e.as_ref()),
+      Self::MalformedPayload(e) => Some(e),
+      Self::Interrupted(e) => Some(e),
+      Self::InvalidUrl(_) | Self::UnknownDevice(_) | Self::Result { .. } =>
+        None,
+    }
+  }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct DeviceList {
-  payload: DeviceListPayload,
+impl From<tungstenite::Error> for Error {
+  fn from(e: tungstenite::Error) -> Self { Self::Protocol(Box::new(e)) }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct DevicePayload {
-  percentage: u64,
+impl From<std::io::Error> for Error {
+  fn from(e: std::io::Error) -> Self {
+    Self::Protocol(Box::new(tungstenite::Error::Io(e)))
+  }
 }
 
-impl DevicePayload {
-  pub const fn percentage(&self) -> u64 { self.percentage }
+impl From<serde_json::Error> for Error {
+  fn from(e: serde_json::Error) -> Self { Self::MalformedPayload(e) }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Device {
-  payload: DevicePayload,
+impl From<Interrupted> for Error {
+  fn from(e: Interrupted) -> Self { Self::Interrupted(e) }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// How often a read waiting on G HUB checks whether it has been cancelled
+const CANCEL_INTERVAL: Duration = Duration::from_millis(100);
+
+type Stream = tungstenite::WebSocket<TcpStream>;
+
+/// Read a single message, giving up once `deadline` passes or as soon as
+/// `cancelled` says so
+fn read(
+  stream: &mut Stream,
+  deadline: Instant,
+  cancelled: &impl Fn() -> bool,
+) -> Result<Message> {
+  loop {
+    if cancelled() {
+      return Err(Interrupted::Cancelled.into());
+    }
+
+    let remaining = deadline.saturating_duration_since(Instant::now());
+
+    if remaining.is_zero() {
+      return Err(Interrupted::Timeout.into());
+    }
+
+    // Waiting in short slices, so that cancellations are noticed quickly
+    stream
+      .get_ref()
+      .set_read_timeout(Some(remaining.min(CANCEL_INTERVAL)))?;
+
+    match stream.read_message() {
+      Ok(message) => return Ok(message),
+      Err(tungstenite::Error::Io(e))
+        if matches!(
+          e.kind(),
+          std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        ) => {}
+      Err(e) => return Err(e.into()),
+    }
+  }
 }
 
-impl Device {
-  pub const fn payload(&self) -> &DevicePayload { &self.payload }
+/// Parse the configured G HUB URL, refusing anything but a `ws://` URL with a
+/// host
+///
+/// G HUB only ever listens on plain `ws://`, so a `wss://` URL can't be
+/// anything but a mistake.
+fn endpoint(url: &str) -> Result<url::Url> {
+  match url::Url::parse(url) {
+    Ok(endpoint) if endpoint.scheme() == "ws" && endpoint.has_host() =>
+      Ok(endpoint),
+    Ok(_) => Err(Error::InvalidUrl(format!("'{url}' is not a ws:// url"))),
+    Err(e) => Err(Error::InvalidUrl(format!("'{url}' is not a url ({e})"))),
+  }
 }
 
 /// Create a connection to the Logitech G HUB `WebSocket` (backtick-ed because
 /// rustfmt is forcing me to)
-fn connection() -> tungstenite::WebSocket<
-  tungstenite::stream::MaybeTlsStream<std::net::TcpStream>,
-> {
-  // This will never fail because the URL is hardcoded
-  let url = url::Url::parse("ws://localhost:9010").unwrap();
-
-  let (mut ws_stream, _) = tungstenite::connect({
-    // This will never fail because the URL is valid
-    let mut request = url.into_client_request().unwrap();
-
-    // https://github.com/snapview/tungstenite-rs/issues/279
-    // https://github.com/snapview/tungstenite-rs/issues/145#issuecomment-713581499
-    //
-    // This unwrap will never fail either because we are parsing a hardcoded,
-    // known string
-    request
-      .headers_mut()
-      .insert("Sec-WebSocket-Protocol", "json".parse().unwrap());
-
-    request
-  })
-  .unwrap_or_else(|_| {
-    quit("failed to connect to the logitech g hub websocket. is it running?");
-  });
-
-  ws_stream.read_message().unwrap_or_else(|_| {
-    quit("failed to read message from the logitech g hub websocket");
-  });
-
-  ws_stream
-}
-
-/// Get a list of only wireless devices from the Logitech G HUB `WebSocket`
-pub fn wireless_devices() -> HashMap<String, DeviceInfo> {
-  let mut stream = connection();
-
-  stream
-    .write_message(Message::binary(
-      serde_json::json!({
-        "path": "/devices/list",
-        "verb": "GET"
-      })
-      .to_string(),
-    ))
-    .unwrap_or_else(|_| {
-      quit("failed to write message to the logitech g hub websocket");
-    });
+fn connection(
+  url: &url::Url,
+  timeout: Duration,
+  cancelled: &impl Fn() -> bool,
+) -> Result<Stream> {
+  let connect_error =
+    |e: std::io::Error| Error::Connect(Box::new(tungstenite::Error::Io(e)));
+  // `ws://` URLs without a port fall back to port 80
+  let addresses = url.socket_addrs(|| None).map_err(connect_error)?;
+  let mut last_error = std::io::Error::new(
+    std::io::ErrorKind::NotFound,
+    "no addresses to connect to",
+  );
+  let mut stream = None;
+
+  // `localhost` can resolve to more than one address, only some of which G
+  // HUB might be listening on
+  for address in addresses {
+    match TcpStream::connect_timeout(&address, timeout) {
+      Ok(connected) => {
+        stream = Some(connected);
+
+        break;
+      }
+      Err(e) => last_error = e,
+    }
+  }
+
+  let stream = stream.ok_or_else(|| connect_error(last_error))?;
+
+  // Making sure a G HUB that accepts connections but never finishes the
+  // handshake doesn't wedge us either
+  stream.set_read_timeout(Some(timeout))?;
+  stream.set_write_timeout(Some(timeout))?;
 
-  // This will never fail because if we even got this far, the `WebSocket` is
-  // working.
-  let devices = serde_json::from_value::<DeviceList>(
-    serde_json::from_str(&stream.read_message().unwrap().into_text().unwrap())
-      .unwrap(),
+  let (mut ws_stream, _) = tungstenite::client(
+    {
+      // This will never fail because the URL was checked to be a `ws://` URL
+      // with a host
+      let mut request = url.clone().into_client_request().unwrap();
+
+      // https://github.com/snapview/tungstenite-rs/issues/279
+      // https://github.com/snapview/tungstenite-rs/issues/145#issuecomment-713581499
+      //
+      // This unwrap will never fail either because we are parsing a
+      // hardcoded, known string
+      request
+        .headers_mut()
+        .insert("Sec-WebSocket-Protocol", "json".parse().unwrap());
+
+      request
+    },
+    stream,
   )
-  .unwrap();
-  let wireless = devices
-    .payload
-    .device_infos
-    .iter()
-    .filter(|device_info| device_info.connection_type == "WIRELESS")
-    .map(DeviceInfo::from_device_info)
-    .collect::<Vec<DeviceInfo>>();
-  let mut mapped = HashMap::new();
-
-  for device in wireless {
-    mapped.insert(device.display_name.clone(), device);
-  }
-
-  // Adding a dummy device to the device list for testing purposes.
-  //
-  // I'm also going to keep this in because it's a nice way for the user to make
-  // sure everything is working properly.
-  mapped.insert(
-    "Dummy (Debug)".to_string(),
-    DeviceInfo::new("dummy_debug", "WIRELESS", "MOUSE", "Dummy (Debug)"),
-  );
+  .map_err(|e| match e {
+    HandshakeError::Interrupted(_) => Interrupted::Timeout.into(),
+    HandshakeError::Failure(e) => Error::Connect(Box::new(e)),
+  })?;
 
-  mapped
+  read(&mut ws_stream, Instant::now() + timeout, cancelled)?;
+
+  Ok(ws_stream)
+}
+
+/// A long-lived session with the Logitech G HUB `WebSocket`
+///
+/// The underlying `WebSocket` is opened lazily on the first request and is
+/// reused for every request after that. It is only re-opened if the socket
+/// drops.
+pub struct Client {
+  stream: Option<Stream>,
+  /// Device IDs as of the last device listing
+  device_ids: HashSet<String>,
+  /// The message ID of the last request
+  msg_id: u64,
+  config: LogitechConfig,
+  url: url::Url,
+  /// Counts requests, so that whatever request is in flight can be cancelled
+  requests: Arc<InFlight>,
+  /// The number of the request in flight, or of the last one
+  request: u64,
+  /// State change pushes read while waiting on a reply, which `listen` hands
+  /// out before anything newer
+  pushes: VecDeque<Response>,
 }
 
-/// Get the battery percentage of a specific wireless device
-pub fn device(display_name: &str) -> Device {
-  if display_name == "Dummy (Debug)" {
-    return Device {
-      payload: DevicePayload { percentage: 100 },
+impl Client {
+  /// Create a client for the configured G HUB URL, which is refused if it
+  /// isn't a `ws://` URL, rather than quietly talking to some other G HUB
+  pub fn new(config: &LogitechConfig) -> Result<Self> {
+    Ok(Self::with_url(config, endpoint(&config.url)?))
+  }
+
+  fn with_url(config: &LogitechConfig, url: url::Url) -> Self {
+    Self {
+      stream: None,
+      device_ids: HashSet::new(),
+      msg_id: 0,
+      config: config.clone(),
+      url,
+      requests: Arc::new(InFlight::default()),
+      request: 0,
+      pushes: VecDeque::new(),
+    }
+  }
+
+  /// Whether the `WebSocket` is open, as of the last request
+  ///
+  /// If a request failed because G HUB couldn't be reached, this stays `false`
+  /// until a later request manages to reconnect.
+  pub const fn is_connected(&self) -> bool { self.stream.is_some() }
+
+  /// Whether the request in flight has been cancelled, checkable without
+  /// borrowing the client
+  fn cancelled(&self) -> impl Fn() -> bool {
+    let requests = self.requests.clone();
+    let request = self.request;
+
+    move || requests.is_cancelled(request)
+  }
+
+  /// Get the open `WebSocket`, connecting if there isn't one yet
+  fn stream(&mut self) -> Result<&mut Stream> {
+    if let Some(ref mut stream) = self.stream {
+      Ok(stream)
+    } else {
+      debug!("opening logitech g hub websocket at {}", self.url);
+
+      Ok(self.stream.insert(connection(
+        &self.url,
+        Duration::from_millis(self.config.connect_timeout_ms),
+        &self.cancelled(),
+      )?))
+    }
+  }
+
+  /// Build a request with a fresh message ID
+  fn next_request<'a>(&mut self, verb: Verb, path: &'a str) -> Request<'a> {
+    self.msg_id += 1;
+
+    Request {
+      msg_id: self.msg_id.to_string(),
+      verb,
+      path,
+      payload: None,
+    }
+  }
+
+  /// Write a single request and read until its reply comes in, or until the
+  /// read timeout passes
+  fn exchange(&mut self, request: &Request<'_>) -> Result<Response> {
+    let deadline =
+      Instant::now() + Duration::from_millis(self.config.read_timeout_ms);
+    let cancelled = self.cancelled();
+
+    self
+      .stream()?
+      .write_message(Message::binary(serde_json::to_string(request)?))?;
+
+    // This will never fail because the request was just written to the open
+    // `WebSocket`.
+    let stream = self.stream.as_mut().unwrap();
+
+    loop {
+      let response = serde_json::from_str::<Response>(
+        &read(stream, deadline, &cancelled)?.into_text()?,
+      )?;
+
+      // Pushes, and replies to anything asked before, aren't the reply we're
+      // waiting for
+      if response.msg_id == request.msg_id {
+        return Ok(response);
+      }
+
+      // Pushes can land between subscribing to one path and the next, so
+      // they're kept for `listen` rather than lost
+      if response.msg_id.is_empty()
+        && matches!(
+          response.path.as_str(),
+          BATTERY_STATE_CHANGED | DEVICE_STATE_CHANGED
+        )
+      {
+        trace!("holding on to push for '{}'", response.path);
+
+        self.pushes.push_back(response);
+
+        continue;
+      }
+
+      trace!(
+        "skipping message '{}' for '{}' while waiting for '{}'",
+        response.msg_id,
+        response.path,
+        request.msg_id
+      );
+    }
+  }
+
+  /// Send a request over the open `WebSocket`, reconnecting once if the
+  /// socket has dropped since the last request, and read the payload of its
+  /// reply
+  fn request<T: serde::de::DeserializeOwned>(
+    &mut self,
+    verb: Verb,
+    path: &str,
+  ) -> Result<T> {
+    let request = self.next_request(verb, path);
+
+    // Only cancellations from here on cancel this request
+    self.request = self.requests.start();
+
+    let response = match self.exchange(&request) {
+      Ok(response) => response,
+      // The `WebSocket` is still fine, and a late reply won't be mistaken for
+      // the reply to a later request because of its message ID.
+      Err(e @ Error::Interrupted(_)) => return Err(e),
+      Err(e) => {
+        warn!("lost logitech g hub websocket, reconnecting: {}", e);
+
+        self.stream = None;
+
+        self.exchange(&request)?
+      }
     };
+
+    Ok(serde_json::from_value(response.into_success()?.payload)?)
   }
 
-  let mut stream = connection();
-
-  stream
-    .write_message(Message::binary(
-      // The `unwrap` for `get` should never fail because we know the devices that
-      // are available, and if the user unplugs one of their devices mid-battery state check,
-      // that's on them. :P
-      serde_json::json!({
-        "path": format!("/battery/{}/state", wireless_devices().get(display_name).unwrap().id),
-        "verb": "GET"
-      })
-      .to_string(),
-    ))
-    .unwrap_or_else(|_| {
-      quit("failed to write message to the logitech g hub websocket");
+  /// Get a list of only wireless devices from the Logitech G HUB `WebSocket`,
+  /// keyed by their G HUB ID
+  pub fn wireless_devices(&mut self) -> Result<HashMap<String, DeviceInfo>> {
+    let devices = self.request::<DeviceList>(Verb::Get, "/devices/list")?;
+    let mapped = crate::source::label_devices(
+      devices
+        .device_infos
+        .iter()
+        .filter(|device_info| device_info.connection_type() == "WIRELESS")
+        .map(DeviceInfo::from_device_info)
+        .collect(),
+    );
+
+    self.device_ids = mapped.keys().cloned().collect();
+
+    Ok(mapped)
+  }
+
+  /// Get the battery state of a specific wireless device by its G HUB ID
+  pub fn battery_state(&mut self, id: &str) -> Result<BatteryState> {
+    // Only re-list the devices if we haven't seen this one yet
+    if !self.device_ids.contains(id) {
+      self.wireless_devices()?;
+    }
+
+    if !self.device_ids.contains(id) {
+      return Err(Error::UnknownDevice(id.to_string()));
+    }
+
+    self.request(Verb::Get, &format!("/battery/{id}/state"))
+  }
+
+  /// Turn a message into a state change push, if it is one
+  fn push(response: Response) -> Option<Push> {
+    match response.path.as_str() {
+      BATTERY_STATE_CHANGED => {
+        match serde_json::from_value::<BatteryStateChange>(response.payload) {
+          Ok(change) => {
+            trace!(
+              "received battery state change for '{}'",
+              change.device_id()
+            );
+
+            Some(Push::BatteryState(change))
+          }
+          Err(e) => {
+            debug!("skipping malformed battery state change: {}", e);
+
+            None
+          }
+        }
+      }
+      DEVICE_STATE_CHANGED => {
+        trace!("received device state change");
+
+        Some(Push::DeviceState)
+      }
+      _ => None,
+    }
+  }
+
+  /// Subscribe to battery and device state changes, forwarding each one to
+  /// `sender` as soon as G HUB pushes it
+  ///
+  /// This blocks for as long as `sender` has a receiver, so it should be given
+  /// its own thread and its own `Client`. If the `WebSocket` drops, or G HUB
+  /// can't be reached at all, the subscription is re-established on a new one
+  /// with exponential backoff.
+  pub fn listen(&mut self, sender: &Sender<Push>) {
+    let mut backoff = Backoff::default();
+
+    loop {
+      let subscribed = [BATTERY_STATE_CHANGED, DEVICE_STATE_CHANGED]
+        .into_iter()
+        .try_for_each(|path| {
+          self
+            .request::<serde_json::Value>(Verb::Subscribe, path)
+            .map(drop)
+        });
+
+      if let Err(e) = subscribed {
+        debug!("failed to subscribe to state changes: {}", e);
+
+        self.stream = None;
+
+        backoff.wait();
+
+        continue;
+      }
+
+      debug!("subscribed to battery and device state changes");
+
+      for response in std::mem::take(&mut self.pushes) {
+        if let Some(push) = Self::push(response) {
+          if sender.send(push).is_err() {
+            return;
+          }
+
+          backoff = Backoff::default();
+        }
+      }
+
+      // This will never fail because the subscriptions were just written to
+      // the open `WebSocket`.
+      let stream = self.stream.as_mut().unwrap();
+
+      // Pushes can be a long time apart, so they're waited on for as long as
+      // it takes
+      if let Err(e) = stream.get_ref().set_read_timeout(None) {
+        warn!("failed to wait on state changes, resubscribing: {}", e);
+
+        self.stream = None;
+
+        backoff.wait();
+
+        continue;
+      }
+
+      loop {
+        let message = match stream.read_message() {
+          Ok(message) => message,
+          Err(e) => {
+            warn!("lost state change subscription, resubscribing: {}", e);
+
+            self.stream = None;
+
+            break;
+          }
+        };
+
+        // Anything that isn't a state change push is skipped.
+        let Ok(Ok(response)) = message
+          .into_text()
+          .map(|text| serde_json::from_str::<Response>(&text))
+        else {
+          continue;
+        };
+        let Some(push) = Self::push(response) else {
+          continue;
+        };
+
+        // The receiver is gone, so nobody is listening anymore
+        if sender.send(push).is_err() {
+          return;
+        }
+
+        // Only a subscription which has pushed something is taken for a
+        // healthy one, so that a G HUB which drops every subscription
+        // straight away is backed off from too
+        backoff = Backoff::default();
+      }
+
+      backoff.wait();
+    }
+  }
+}
+
+impl crate::source::BatterySource for Client {
+  fn name(&self) -> &'static str { "logitech g hub" }
+
+  fn is_connected(&self) -> bool { self.is_connected() }
+
+  fn devices(&mut self) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    Ok(self.wireless_devices()?)
+  }
+
+  fn battery(&mut self, id: &str) -> crate::source::Result<BatteryState> {
+    Ok(self.battery_state(id)?)
+  }
+
+  fn subscribe(&mut self, sender: Sender<Push>) {
+    let mut client = Self::with_url(&self.config, self.url.clone());
+
+    // Listening blocks, so it gets its own thread and its own `WebSocket`
+    std::thread::spawn(move || client.listen(&sender));
+  }
+
+  fn canceller(&self) -> crate::source::Canceller {
+    crate::source::Canceller::new(self.requests.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc;
+
+  use super::*;
+  use crate::{mock_ghub::MockGhub, source::BatterySource};
+
+  /// Start a mock G HUB on any free port, already scripted with `script`
+  fn mock(script: &[&str]) -> MockGhub {
+    let mock = MockGhub::bind("127.0.0.1:0").unwrap();
+
+    for line in script {
+      mock.run(line).unwrap();
+    }
+
+    mock
+  }
+
+  fn client_for(url: &str) -> Result<Client> {
+    Client::new(&LogitechConfig {
+      url: url.to_string(),
+      ..LogitechConfig::default()
+    })
+  }
+
+  fn client(url: &str) -> Client {
+    Client::new(&LogitechConfig {
+      url: url.to_string(),
+      connect_timeout_ms: 1000,
+      read_timeout_ms: 1000,
+    })
+    .unwrap()
+  }
+
+  /// Keep running `line` until `pushes` receives a push that `expected`
+  /// matches, since pushes only reach a listener once it has subscribed
+  fn push_from(
+    mock: &MockGhub,
+    line: &str,
+    pushes: &mpsc::Receiver<Push>,
+    expected: impl Fn(&Push) -> bool,
+  ) -> Push {
+    for _ in 0..50 {
+      mock.run(line).unwrap();
+
+      while let Ok(push) = pushes.recv_timeout(Duration::from_millis(100)) {
+        if expected(&push) {
+          return push;
+        }
+      }
+    }
+
+    panic!("nothing expected was pushed after '{line}'");
+  }
+
+  fn percentage(percentage: u64) -> impl Fn(&Push) -> bool {
+    move |push| {
+      matches!(
+        push,
+        Push::BatteryState(change) if change.state().percentage() == percentage
+      )
+    }
+  }
+
+  #[test]
+  fn lists_only_wireless_devices() {
+    let mock = mock(&[
+      "device 1 WIRELESS G305",
+      "device 2 WIRED G502",
+      "battery 1 57 charging 3900mV",
+    ]);
+    let mut client = client(&mock.url());
+    let devices = client.wireless_devices().unwrap();
+
+    assert_eq!(devices.keys().collect::<Vec<_>>(), ["1"]);
+    assert_eq!(devices["1"].label, "G305");
+
+    let battery_state = client.battery_state("1").unwrap();
+
+    assert_eq!(battery_state.percentage(), 57);
+    assert!(battery_state.is_charging());
+    assert_eq!(battery_state.millivolts(), Some(3900));
+    assert!(matches!(
+      client.battery_state("2"),
+      Err(Error::UnknownDevice(id)) if id == "2"
+    ));
+  }
+
+  #[test]
+  fn numbers_duplicate_display_names() {
+    let mock = mock(&[
+      "device b WIRELESS G305",
+      "device a WIRELESS G305",
+      "device c WIRELESS G502",
+    ]);
+    let devices = client(&mock.url()).wireless_devices().unwrap();
+
+    assert_eq!(devices["a"].label, "G305");
+    assert_eq!(devices["b"].label, "G305 (2)");
+    assert_eq!(devices["c"].label, "G502");
+  }
+
+  #[test]
+  fn fails_with_the_result_code() {
+    let mock = mock(&["device 1 WIRELESS G305", "fail /battery/1/state BUSY"]);
+    let mut client = client(&mock.url());
+
+    assert!(matches!(
+      client.battery_state("1"),
+      Err(Error::Result { code, .. }) if code == "BUSY"
+    ));
+    // Only the next request fails, and the connection is still good
+    assert!(client.is_connected());
+    assert_eq!(client.battery_state("1").unwrap().percentage(), 100);
+  }
+
+  #[test]
+  fn refuses_anything_but_a_ws_url() {
+    for url in [
+      "wss://localhost:9010",
+      "http://localhost:9010",
+      "localhost:9010",
+      "ws://",
+    ] {
+      assert!(
+        matches!(client_for(url), Err(Error::InvalidUrl(_))),
+        "{url} was accepted"
+      );
+    }
+
+    assert!(client_for("ws://127.0.0.1:9010").is_ok());
+  }
+
+  #[test]
+  fn times_out_on_a_wedged_ghub() {
+    let mock = mock(&["device 1 WIRELESS G305", "hang /battery/1/state"]);
+    let mut client = Client::new(&LogitechConfig {
+      url: mock.url(),
+      connect_timeout_ms: 1000,
+      read_timeout_ms: 200,
+    })
+    .unwrap();
+
+    // Even passed along as any source's error, it can be told apart
+    let error = client.battery("1").unwrap_err();
+
+    assert_eq!(Interrupted::of(error.as_ref()), Some(Interrupted::Timeout));
+    // Only the next request hangs, and the connection is still good
+    assert!(client.is_connected());
+    assert_eq!(client.battery_state("1").unwrap().percentage(), 100);
+  }
+
+  #[test]
+  fn cancels_only_the_request_in_flight() {
+    let mock = mock(&["device 1 WIRELESS G305"]);
+    let mut client = client(&mock.url());
+    let canceller = client.canceller();
+
+    // Nothing is in flight yet, so there's nothing to cancel
+    canceller.cancel();
+    client.battery_state("1").unwrap();
+    mock.run("hang /battery/1/state").unwrap();
+
+    let hung = std::thread::spawn(move || {
+      let started = Instant::now();
+      let battery_state = client.battery_state("1");
+
+      (client, battery_state, started.elapsed())
     });
 
-  // Once again, this should never fail because if we even got this far, the
-  // `WebSocket` is working.
-  serde_json::from_value::<Device>(
-    serde_json::from_str(&stream.read_message().unwrap().into_text().unwrap())
-      .unwrap(),
-  )
-  .unwrap()
+    std::thread::sleep(Duration::from_millis(200));
+    canceller.cancel();
+
+    let (mut client, battery_state, waited) = hung.join().unwrap();
+
+    assert!(matches!(
+      battery_state,
+      Err(Error::Interrupted(Interrupted::Cancelled))
+    ));
+    assert!(waited < Duration::from_secs(1));
+    assert_eq!(client.battery_state("1").unwrap().percentage(), 100);
+  }
+
+  #[test]
+  fn reconnects_after_a_drop() {
+    let mock = mock(&["device 1 WIRELESS G305"]);
+    let mut client = client(&mock.url());
+
+    client.battery_state("1").unwrap();
+    mock.run("drop").unwrap();
+    mock.run("battery 1 42").unwrap();
+
+    assert_eq!(client.battery_state("1").unwrap().percentage(), 42);
+    assert!(client.is_connected());
+  }
+
+  #[test]
+  fn reconnects_once_ghub_is_back() {
+    let mock = mock(&["device 1 WIRELESS G305"]);
+    let address = mock.url().replace("ws://", "");
+    let mut client = client(&mock.url());
+
+    client.wireless_devices().unwrap();
+    drop(mock);
+
+    assert!(matches!(client.wireless_devices(), Err(Error::Connect(_))));
+    assert!(!client.is_connected());
+
+    let mock = MockGhub::bind(&address).unwrap();
+
+    mock.run("device 2 WIRELESS G502").unwrap();
+
+    assert!(client.wireless_devices().unwrap().contains_key("2"));
+    assert!(client.is_connected());
+  }
+
+  #[test]
+  fn resubscribes_once_ghub_is_back() {
+    let mock = mock(&["device 1 WIRELESS G305"]);
+    let address = mock.url().replace("ws://", "");
+    let (sender, pushes) = mpsc::channel();
+
+    client(&mock.url()).subscribe(sender);
+    push_from(&mock, "battery 1 50", &pushes, percentage(50));
+    drop(mock);
+
+    // Resubscribing backs off while G HUB is gone
+    let mock = MockGhub::bind(&address).unwrap();
+
+    mock.run("device 1 WIRELESS G305").unwrap();
+    push_from(&mock, "battery 1 40", &pushes, percentage(40));
+  }
+
+  #[test]
+  fn forwards_pushes() {
+    let mock = mock(&["device 1 WIRELESS G305"]);
+    let (sender, pushes) = mpsc::channel();
+
+    client(&mock.url()).subscribe(sender);
+
+    let Push::BatteryState(change) =
+      push_from(&mock, "battery 1 12 low", &pushes, percentage(12))
+    else {
+      unreachable!();
+    };
+
+    assert_eq!(change.device_id(), "1");
+    assert!(change.state().is_low());
+
+    mock.run("remove 1").unwrap();
+
+    assert!(std::iter::from_fn(|| pushes
+      .recv_timeout(Duration::from_secs(1))
+      .ok())
+    .any(|push| matches!(push, Push::DeviceState)));
+  }
+
+  #[test]
+  fn resubscribes_after_a_drop() {
+    let mock = mock(&["device 1 WIRELESS G305"]);
+    let (sender, pushes) = mpsc::channel();
+
+    client(&mock.url()).subscribe(sender);
+    push_from(&mock, "battery 1 50", &pushes, percentage(50));
+    mock.run("drop").unwrap();
+    push_from(&mock, "battery 1 40", &pushes, percentage(40));
+  }
+
+  #[test]
+  fn keeps_pushes_read_while_waiting_on_a_reply() {
+    let mock = mock(&["device 1 WIRELESS G305"]);
+    let mut client = client(&mock.url());
+    let (sender, pushes) = mpsc::channel();
+
+    client
+      .request::<serde_json::Value>(Verb::Subscribe, BATTERY_STATE_CHANGED)
+      .unwrap();
+    mock.run("battery 1 30").unwrap();
+    // Give the push time to land ahead of the next reply
+    std::thread::sleep(Duration::from_millis(200));
+    client.wireless_devices().unwrap();
+    std::thread::spawn(move || client.listen(&sender));
+
+    assert!(percentage(30)(
+      &pushes.recv_timeout(Duration::from_secs(1)).unwrap()
+    ));
+  }
 }
@@ -29,18 +29,41 @@
 #![windows_subsystem = "windows"]
 
 mod ascii_art;
+#[cfg(target_os = "linux")]
+mod bluez;
+mod cli;
+mod command;
+mod config;
+#[cfg(target_os = "linux")]
+mod dbus;
+mod gauge;
+mod hidpp;
+#[cfg(target_os = "linux")]
+mod kdeconnect;
 mod logitech;
+#[cfg(test)]
+mod mock_ghub;
+mod selection;
+mod simulation;
+mod source;
+mod sysfs;
 mod tray;
+#[cfg(target_os = "linux")]
+mod upower;
 
 #[macro_use]
 extern crate log;
 
+#[cfg(windows)]
 use winapi::{
   um,
   um::{wincon, winuser},
 };
 
 fn main() {
+  // The log window is a console which is hidden until it's asked for from the
+  // tray menu. Everywhere else, logs just go wherever elem was started from.
+  #[cfg(windows)]
   unsafe {
     um::consoleapi::AllocConsole();
 
@@ -57,9 +80,36 @@ fn main() {
     );
 
     winuser::ShowWindow(wincon::GetConsoleWindow(), winuser::SW_HIDE);
-    std::env::set_var("RUST_LOG", "elem=trace");
-    pretty_env_logger::init();
-    info!("starting elem");
-    tray::Tray::new(std::env::args().nth(1)).run();
   }
+
+  std::env::set_var("RUST_LOG", "elem=trace");
+  pretty_env_logger::init();
+
+  let args = cli::Args::parse(std::env::args().skip(1))
+    .unwrap_or_else(|e| tray::quit(&format!("{e}\n\n{}", cli::USAGE)));
+
+  if args.help {
+    // There's no terminal to print to on Windows
+    #[cfg(windows)]
+    tray::message_box(cli::USAGE);
+    #[cfg(not(windows))]
+    println!("{}", cli::USAGE);
+
+    return;
+  }
+
+  info!("starting elem");
+
+  let mut config = config::Config::load();
+
+  if let Some(url) = args.ghub_url {
+    config.logitech.url = url;
+  }
+
+  tray::Tray::new(
+    args.update_frequency,
+    source::from_config(&config).unwrap_or_else(|e| tray::quit(&e.to_string())),
+    ascii_art::Renderer::from_config(&config.icon),
+  )
+  .run();
 }
@@ -0,0 +1,463 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+//! A stand-in for the Logitech G HUB `WebSocket`, shared by the `mock_ghub`
+//! binary and the tests
+//!
+//! The mock speaks the `json` subprotocol, answers `/devices/list` and
+//! `/battery/{id}/state`, and accepts subscriptions. Everything else about it
+//! is scripted one command at a time:
+//!
+//! - `device <id> <connection type> <display name>`: add or replace a device,
+//!   starting at 100%
+//! - `remove <id>`: remove a device
+//! - `battery <id> <percentage> [charging] [low] [critical] [<n>mV]`: change a
+//!   device's battery state
+//! - `fail <path> <code>`: reply to the next request for `path` with `code`
+//!   instead of `SUCCESS`
+//! - `hang <path>`: never reply to the next request for `path`, like a wedged G
+//!   HUB would
+//! - `drop`: drop every open connection, like a restarting G HUB would
+//! - `sleep <milliseconds>`: wait before running the next command
+//!
+//! Adding, removing, and changing devices is pushed to every connection which
+//! subscribed to the matching state change.
+
+use std::{
+  collections::{HashMap, HashSet},
+  net::{Shutdown, SocketAddr, TcpListener, TcpStream},
+  str::SplitWhitespace,
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex,
+  },
+  time::Duration,
+};
+
+use tungstenite::{handshake::server, Message};
+
+const BATTERY_STATE_CHANGED: &str = "/battery/state/changed";
+const DEVICE_STATE_CHANGED: &str = "/devices/state/changed";
+
+/// How long a connection waits for a request before checking whether it has
+/// anything to push
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// An open connection, as far as scripted commands are concerned
+struct Connection {
+  /// State changes to push, only sent if the connection subscribed to their
+  /// path
+  pushes: mpsc::Sender<(String, serde_json::Value)>,
+  /// Shut down to drop the connection without closing it properly
+  stream: TcpStream,
+}
+
+struct MockDevice {
+  id: String,
+  connection_type: String,
+  display_name: String,
+  /// The battery state payload, minus the device ID
+  battery: serde_json::Value,
+}
+
+#[derive(Default)]
+struct State {
+  devices: Vec<MockDevice>,
+  /// Result codes to fail the next request for each path with
+  failures: HashMap<String, String>,
+  /// Paths to leave the next request for unanswered
+  hangs: HashSet<String>,
+  connections: Vec<Connection>,
+}
+
+impl State {
+  /// Push a state change to every open connection
+  fn broadcast(&mut self, path: &str, payload: &serde_json::Value) {
+    self.connections.retain(|connection| {
+      connection
+        .pushes
+        .send((path.to_string(), payload.clone()))
+        .is_ok()
+    });
+  }
+
+  /// Replace, add, or remove (given `None`) a device, pushing the change
+  fn replace_device(&mut self, id: &str, device: Option<MockDevice>) {
+    self.devices.retain(|device| device.id != id);
+    self.devices.extend(device);
+    self.broadcast(DEVICE_STATE_CHANGED, &serde_json::json!({ "id": id }));
+  }
+
+  /// Work out the reply to a single request, like G HUB would, if it's
+  /// answered at all
+  fn reply(
+    &mut self,
+    request: &serde_json::Value,
+  ) -> Option<serde_json::Value> {
+    let path = request["path"].as_str().unwrap_or_default();
+    let verb = request["verb"].as_str().unwrap_or_default();
+    let reply = |code: &str, payload: Option<serde_json::Value>| {
+      let mut reply = serde_json::json!({
+        "msgId": request.get("msgId").cloned().unwrap_or_default(),
+        "verb": verb,
+        "path": path,
+        "origin": "backend",
+        "result": { "code": code }
+      });
+
+      if let Some(payload) = payload {
+        reply["payload"] = payload;
+      }
+
+      reply
+    };
+
+    if self.hangs.remove(path) {
+      debug!("leaving '{}' unanswered", path);
+
+      return None;
+    }
+
+    if let Some(code) = self.failures.remove(path) {
+      debug!("failing '{}' with {}", path, code);
+
+      return Some(reply(&code, None));
+    }
+
+    Some(match verb {
+      "SUBSCRIBE" => reply("SUCCESS", None),
+      "GET" if path == "/devices/list" => reply(
+        "SUCCESS",
+        Some(serde_json::json!({
+          "deviceInfos": self
+            .devices
+            .iter()
+            .map(|device| serde_json::json!({
+              "id": device.id,
+              "connectionType": device.connection_type,
+              "deviceType": "MOUSE",
+              "displayName": device.display_name
+            }))
+            .collect::<Vec<_>>()
+        })),
+      ),
+      "GET" => path
+        .strip_prefix("/battery/")
+        .and_then(|rest| rest.strip_suffix("/state"))
+        .and_then(|id| self.devices.iter().find(|device| device.id == id))
+        .map_or_else(
+          || reply("NO_SUCH_PATH", None),
+          |device| {
+            let mut battery = device.battery.clone();
+
+            battery["deviceId"] = device.id.clone().into();
+
+            reply("SUCCESS", Some(battery))
+          },
+        ),
+      _ => reply("NO_SUCH_PATH", None),
+    })
+  }
+}
+
+/// Answer requests and push subscribed state changes on a single connection
+/// until either side drops it
+// The handshake callback's error type is dictated by tungstenite
+#[allow(clippy::result_large_err)]
+fn serve(
+  stream: TcpStream,
+  state: &Mutex<State>,
+) -> Result<(), Box<dyn std::error::Error>> {
+  let connection = stream.try_clone()?;
+  // The `json` subprotocol is echoed back, like G HUB does
+  let handshake = tungstenite::accept_hdr(
+    stream,
+    |request: &server::Request, mut response: server::Response| {
+      if let Some(protocol) = request.headers().get("Sec-WebSocket-Protocol") {
+        response
+          .headers_mut()
+          .insert("Sec-WebSocket-Protocol", protocol.clone());
+      }
+
+      Ok(response)
+    },
+  );
+  let mut ws_stream = match handshake {
+    Ok(ws_stream) => ws_stream,
+    Err(e) => {
+      warn!("failed websocket handshake: {}", e);
+
+      return Ok(());
+    }
+  };
+  let (sender, outbox) = mpsc::channel();
+  let mut subscriptions = HashSet::new();
+
+  ws_stream.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+  state.lock().unwrap().connections.push(Connection {
+    pushes: sender,
+    stream: connection,
+  });
+
+  // G HUB greets every new connection before anything is asked of it
+  ws_stream.write_message(Message::text(
+    serde_json::json!({
+      "msgId": "",
+      "verb": "SET",
+      "path": "/mock/hello",
+      "origin": "backend",
+      "payload": {}
+    })
+    .to_string(),
+  ))?;
+
+  loop {
+    for (path, payload) in outbox.try_iter() {
+      if subscriptions.contains(&path) {
+        trace!("pushing '{}'", path);
+        ws_stream.write_message(Message::text(
+          serde_json::json!({
+            "msgId": "",
+            "verb": "SET",
+            "path": path,
+            "origin": "backend",
+            "payload": payload
+          })
+          .to_string(),
+        ))?;
+      }
+    }
+
+    let message = match ws_stream.read_message() {
+      Ok(message) => message,
+      Err(tungstenite::Error::Io(e))
+        if matches!(
+          e.kind(),
+          std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+        ) =>
+        continue,
+      Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+      Err(e) => return Err(e.into()),
+    };
+
+    // Pings and closes are already taken care of by tungstenite
+    if !(message.is_text() || message.is_binary()) {
+      continue;
+    }
+
+    let request =
+      match serde_json::from_str::<serde_json::Value>(message.to_text()?) {
+        Ok(request) => request,
+        Err(e) => {
+          warn!("skipping malformed request: {}", e);
+
+          continue;
+        }
+      };
+
+    trace!("received request: {}", request);
+
+    if request["verb"] == "SUBSCRIBE" {
+      if let Some(path) = request["path"].as_str() {
+        subscriptions.insert(path.to_string());
+      }
+    }
+
+    let reply = state.lock().unwrap().reply(&request);
+
+    if let Some(reply) = reply {
+      ws_stream.write_message(Message::text(reply.to_string()))?;
+    }
+  }
+}
+
+/// Take the next word of a command, complaining about `what` is missing if
+/// there isn't one
+fn word<'a>(
+  words: &mut SplitWhitespace<'a>,
+  what: &str,
+) -> Result<&'a str, String> {
+  words.next().ok_or_else(|| format!("missing {what}"))
+}
+
+/// Run a single scripted command
+fn command(state: &Mutex<State>, line: &str) -> Result<(), String> {
+  let mut words = line.split_whitespace();
+
+  match word(&mut words, "command")? {
+    "device" => {
+      let id = word(&mut words, "device id")?;
+      let connection_type = word(&mut words, "connection type")?;
+      let display_name = words.collect::<Vec<_>>().join(" ");
+
+      state.lock().unwrap().replace_device(
+        id,
+        Some(MockDevice {
+          id: id.to_string(),
+          connection_type: connection_type.to_string(),
+          display_name,
+          battery: serde_json::json!({ "percentage": 100 }),
+        }),
+      );
+    }
+    "remove" => state
+      .lock()
+      .unwrap()
+      .replace_device(word(&mut words, "device id")?, None),
+    "battery" => {
+      let id = word(&mut words, "device id")?;
+      let percentage = word(&mut words, "percentage")?
+        .parse::<u64>()
+        .map_err(|e| format!("invalid percentage: {e}"))?;
+      let mut battery = serde_json::json!({
+        "percentage": percentage,
+        "charging": false,
+        "critical": false,
+        "low": false
+      });
+
+      for flag in words {
+        match flag {
+          "charging" | "critical" | "low" => battery[flag] = true.into(),
+          _ =>
+            battery["millivolts"] = flag
+              .strip_suffix("mV")
+              .and_then(|millivolts| millivolts.parse::<u64>().ok())
+              .ok_or_else(|| format!("unknown battery flag '{flag}'"))?
+              .into(),
+        }
+      }
+
+      let mut state = state.lock().unwrap();
+
+      state
+        .devices
+        .iter_mut()
+        .find(|device| device.id == id)
+        .ok_or_else(|| format!("no device with id '{id}'"))?
+        .battery = battery.clone();
+      battery["deviceId"] = id.into();
+
+      state.broadcast(BATTERY_STATE_CHANGED, &battery);
+    }
+    "fail" => {
+      let path = word(&mut words, "path")?;
+      let code = word(&mut words, "result code")?;
+
+      state
+        .lock()
+        .unwrap()
+        .failures
+        .insert(path.to_string(), code.to_string());
+    }
+    "hang" => {
+      let path = word(&mut words, "path")?;
+
+      state.lock().unwrap().hangs.insert(path.to_string());
+    }
+    "drop" =>
+      for connection in state.lock().unwrap().connections.drain(..) {
+        debug!("dropping connection");
+        // The connection might have already gone away on its own, which is
+        // just as good
+        connection.stream.shutdown(Shutdown::Both).ok();
+      },
+    "sleep" => std::thread::sleep(Duration::from_millis(
+      word(&mut words, "milliseconds")?
+        .parse()
+        .map_err(|e| format!("invalid milliseconds: {e}"))?,
+    )),
+    unknown => return Err(format!("unknown command '{unknown}'")),
+  }
+
+  Ok(())
+}
+
+/// A mock G HUB listening for connections in the background
+///
+/// Dropping the mock stops it listening and drops every open connection, like
+/// G HUB shutting down would.
+pub struct MockGhub {
+  address: SocketAddr,
+  state: Arc<Mutex<State>>,
+  stopped: Arc<AtomicBool>,
+  listener: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MockGhub {
+  /// Start listening on `address`, serving every connection on its own thread
+  ///
+  /// Port `0` picks any free port, which `MockGhub::url` tells.
+  pub fn bind(address: &str) -> std::io::Result<Self> {
+    let listener = TcpListener::bind(address)?;
+    let state = Arc::new(Mutex::new(State::default()));
+    let listener_state = state.clone();
+    let stopped = Arc::new(AtomicBool::new(false));
+    let listener_stopped = stopped.clone();
+    let address = listener.local_addr()?;
+
+    let listener = std::thread::spawn(move || {
+      for stream in listener.incoming() {
+        // The listener goes away along with this thread, freeing up the port
+        if listener_stopped.load(Ordering::Relaxed) {
+          break;
+        }
+
+        let Ok(stream) = stream else {
+          continue;
+        };
+        let state = listener_state.clone();
+
+        std::thread::spawn(move || {
+          if let Err(e) = serve(stream, &state) {
+            debug!("connection closed: {}", e);
+          }
+        });
+      }
+    });
+
+    Ok(Self {
+      address,
+      state,
+      stopped,
+      listener: Some(listener),
+    })
+  }
+
+  /// The `ws://` URL to reach the mock at
+  pub fn url(&self) -> String { format!("ws://{}", self.address) }
+
+  /// Run a single scripted command
+  pub fn run(&self, line: &str) -> Result<(), String> {
+    command(&self.state, line)
+  }
+}
+
+impl Drop for MockGhub {
+  fn drop(&mut self) {
+    self.stopped.store(true, Ordering::Relaxed);
+    self.run("drop").ok();
+
+    // Waking the listener up, so that it notices it has been stopped, and
+    // waiting for it to let go of the port
+    TcpStream::connect(self.address).ok();
+
+    if let Some(listener) = self.listener.take() {
+      listener.join().ok();
+    }
+  }
+}
@@ -0,0 +1,157 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::collections::HashMap;
+
+use crate::source::DeviceInfo;
+
+/// The devices listed in the devices menu, and which one of them is selected
+///
+/// This is kept apart from the tray, so that which device ends up selected can
+/// be worked out without a system tray around.
+#[derive(Default)]
+pub struct Selection {
+  /// Every device in the devices menu, keyed by ID
+  devices: HashMap<String, DeviceInfo>,
+  selected_device_id: Option<String>,
+}
+
+impl Selection {
+  /// The ID of the selected device, along with its label as shown in the
+  /// devices menu
+  pub fn selected_device(&self) -> (Option<String>, String) {
+    (
+      self.selected_device_id.clone(),
+      self
+        .selected_device_id
+        .as_ref()
+        .and_then(|id| self.devices.get(id))
+        .map(|device_info| device_info.label.clone())
+        .unwrap_or_default(),
+    )
+  }
+
+  pub fn is_selected(&self, id: &str) -> bool {
+    self.selected_device_id.as_deref() == Some(id)
+  }
+
+  pub fn select(&mut self, id: &str) {
+    self.selected_device_id = Some(id.to_string());
+  }
+
+  /// Take on a fresh device list, reselecting the previously selected device
+  /// if it's still around, otherwise falling back to the default device
+  pub fn adopt(&mut self, devices: &HashMap<String, DeviceInfo>) {
+    let mut sorted = devices.values().collect::<Vec<_>>();
+
+    sorted.sort_by(|a, b| a.label.cmp(&b.label));
+
+    self.selected_device_id = sorted
+      .iter()
+      .find(|device_info| self.is_selected(&device_info.id))
+      .or_else(|| sorted.last())
+      .map(|device_info| device_info.id.clone());
+    self.devices = devices
+      .iter()
+      .map(|(id, device_info)| {
+        (id.clone(), DeviceInfo::from_device_info(device_info))
+      })
+      .collect();
+  }
+
+  /// Whether the devices menu already lists exactly these devices, down to
+  /// what the "Device Info" menu shows about them
+  pub fn lists_devices(&self, devices: &HashMap<String, DeviceInfo>) -> bool {
+    self.devices == *devices
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn devices(labels: &[(&str, &str)]) -> HashMap<String, DeviceInfo> {
+    labels
+      .iter()
+      .map(|(id, label)| {
+        (
+          id.to_string(),
+          DeviceInfo::new(id, "WIRELESS", "MOUSE", label),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn selects_the_last_device_by_label_at_first() {
+    let mut selection = Selection::default();
+
+    selection.adopt(&devices(&[("1", "G502"), ("2", "G305"), ("3", "G703")]));
+
+    assert_eq!(
+      selection.selected_device(),
+      (Some("3".to_string()), "G703".to_string())
+    );
+  }
+
+  #[test]
+  fn keeps_the_selected_device_while_it_is_around() {
+    let mut selection = Selection::default();
+
+    selection.adopt(&devices(&[("1", "G502"), ("2", "G305")]));
+    selection.select("2");
+    selection.adopt(&devices(&[("1", "G502"), ("2", "G305"), ("3", "G703")]));
+
+    assert!(selection.is_selected("2"));
+  }
+
+  #[test]
+  fn falls_back_when_the_selected_device_goes_away() {
+    let mut selection = Selection::default();
+
+    selection.adopt(&devices(&[("1", "G502"), ("2", "G305")]));
+    selection.select("2");
+    selection.adopt(&devices(&[("1", "G502"), ("3", "G703")]));
+
+    assert!(selection.is_selected("3"));
+  }
+
+  #[test]
+  fn selects_nothing_from_an_empty_list() {
+    let mut selection = Selection::default();
+
+    selection.adopt(&devices(&[("1", "G502")]));
+    selection.adopt(&HashMap::new());
+
+    assert_eq!(selection.selected_device(), (None, String::new()));
+    assert!(selection.lists_devices(&HashMap::new()));
+  }
+
+  #[test]
+  fn lists_devices_only_while_nothing_about_them_changed() {
+    let mut selection = Selection::default();
+    let listed = devices(&[("1", "G502"), ("2", "G305")]);
+
+    selection.adopt(&listed);
+
+    let renamed = devices(&[("1", "G502"), ("2", "G703")]);
+
+    assert!(selection.lists_devices(&listed));
+    assert!(!selection.lists_devices(&devices(&[("1", "G502")])));
+    assert!(!selection.lists_devices(&renamed));
+  }
+}
@@ -0,0 +1,441 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+  collections::HashMap,
+  sync::{mpsc::Sender, Arc, Mutex},
+  time::{Duration, Instant},
+};
+
+use crate::{
+  config::{SimulatedDeviceConfig, SimulationConfig},
+  source::{BatteryState, BatteryStateChange, DeviceInfo, Push},
+};
+
+/// How often the simulation catches up with its clock while subscribed
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const LOW: f64 = 20.0;
+const CRITICAL: f64 = 5.0;
+
+/// A small, seedable random number generator (`SplitMix64`), so that the same
+/// seed always plays out the same way
+struct Random(u64);
+
+impl Random {
+  const fn next(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+
+    let mut z = self.0;
+
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    z ^ (z >> 31)
+  }
+
+  /// Whether something with the probability `probability` happens
+  fn chance(&mut self, probability: f64) -> bool {
+    // 53 bits fit in an `f64` exactly
+    #[allow(clippy::cast_precision_loss)]
+    let sample = (self.next() >> 11) as f64 / (1_u64 << 53) as f64;
+
+    sample < probability
+  }
+}
+
+/// How fast the battery moves at `percentage` along `curve`, in percent per
+/// simulated second
+///
+/// Curves are `[seconds, percentage]` points, and are followed from wherever
+/// the battery currently is, so switching between charging and discharging
+/// picks up at the same percentage on the other curve.
+fn slope(curve: &[(f64, f64)], percentage: f64) -> f64 {
+  curve
+    .windows(2)
+    .find(|points| {
+      let (low, high) = if points[0].1 <= points[1].1 {
+        (points[0].1, points[1].1)
+      } else {
+        (points[1].1, points[0].1)
+      };
+
+      (low..=high).contains(&percentage)
+    })
+    .map_or(0.0, |points| {
+      let seconds = points[1].0 - points[0].0;
+
+      if seconds > 0.0 {
+        (points[1].1 - points[0].1) / seconds
+      } else {
+        0.0
+      }
+    })
+}
+
+struct Device {
+  config: SimulatedDeviceConfig,
+  percentage: f64,
+  charging: bool,
+  /// The simulated second the device comes back at, if it's disconnected
+  disconnected_until: Option<u64>,
+}
+
+impl Device {
+  fn battery_state(&self) -> BatteryState {
+    // Percentages are always clamped between zero and one hundred
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    BatteryState::new(
+      self.percentage.round() as u64,
+      self.charging,
+      None,
+      !self.charging && self.percentage <= CRITICAL,
+      !self.charging && self.percentage <= LOW,
+    )
+  }
+
+  /// Move the device forward by a single simulated second
+  fn tick(&mut self, now: u64, random: &mut Random, pushes: &mut Vec<Push>) {
+    if let Some(until) = self.disconnected_until {
+      if now >= until {
+        debug!("reconnecting simulated device '{}'", self.config.id);
+
+        self.disconnected_until = None;
+
+        pushes.push(Push::DeviceState);
+      }
+
+      return;
+    }
+
+    if random.chance(self.config.disconnects_per_hour / 3600.0) {
+      debug!("disconnecting simulated device '{}'", self.config.id);
+
+      self.disconnected_until = Some(now + self.config.disconnect_seconds);
+
+      pushes.push(Push::DeviceState);
+
+      return;
+    }
+
+    let before = self.battery_state();
+
+    if !self.charging && random.chance(self.config.charges_per_hour / 3600.0) {
+      self.charging = true;
+    }
+
+    let curve = if self.charging {
+      &self.config.charge
+    } else {
+      &self.config.discharge
+    };
+
+    self.percentage =
+      (self.percentage + slope(curve, self.percentage)).clamp(0.0, 100.0);
+
+    // Devices are unplugged as soon as they're full
+    if self.charging && self.percentage >= 100.0 {
+      self.charging = false;
+    }
+
+    let after = self.battery_state();
+
+    if after.percentage() != before.percentage()
+      || after.is_charging() != before.is_charging()
+    {
+      pushes.push(Push::BatteryState(BatteryStateChange::new(
+        &self.config.id,
+        after,
+      )));
+    }
+  }
+}
+
+/// What drives the virtual clock
+enum Clock {
+  /// Real time, sped up `speed` times
+  Real { started: Instant, speed: f64 },
+  /// Nothing but `Simulated::advance_to`, so that tests can play out exactly
+  /// the seconds they want to
+  #[cfg_attr(not(test), allow(dead_code))]
+  Manual,
+}
+
+struct Simulation {
+  devices: Vec<Device>,
+  random: Random,
+  clock: Clock,
+  /// How many simulated seconds have been played out so far
+  now: u64,
+  sender: Option<Sender<Push>>,
+}
+
+impl Simulation {
+  /// Play out every simulated second that has passed on the virtual clock
+  fn advance(&mut self) {
+    if let Clock::Real { started, speed } = self.clock {
+      // The virtual clock never goes backwards or past what fits in a `u64`
+      #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+      self.advance_to((started.elapsed().as_secs_f64() * speed) as u64);
+    }
+  }
+
+  /// Play out every simulated second up to `due`
+  fn advance_to(&mut self, due: u64) {
+    let mut pushes = vec![];
+
+    while self.now < due {
+      self.now += 1;
+
+      for device in &mut self.devices {
+        device.tick(self.now, &mut self.random, &mut pushes);
+      }
+    }
+
+    for push in pushes {
+      if let Some(ref sender) = self.sender {
+        if sender.send(push).is_err() {
+          self.sender = None;
+        }
+      }
+    }
+  }
+}
+
+/// Fake devices whose batteries charge and discharge along configurable
+/// curves, and which randomly disconnect and get plugged in, all driven by a
+/// virtual clock
+///
+/// The same seed and speed always play out the same way, which makes the
+/// simulation handy for demos, screenshots, and trying out the tray without
+/// any real devices.
+pub struct Simulated {
+  simulation: Arc<Mutex<Simulation>>,
+}
+
+impl Simulated {
+  pub fn new(config: &SimulationConfig) -> Self {
+    Self::with_clock(
+      config,
+      Clock::Real {
+        started: Instant::now(),
+        speed: config.speed,
+      },
+    )
+  }
+
+  fn with_clock(config: &SimulationConfig, clock: Clock) -> Self {
+    let devices = config
+      .devices
+      .iter()
+      .enumerate()
+      .map(|(i, device)| {
+        let mut config = device.clone();
+
+        if config.id.is_empty() {
+          config.id = format!("simulated_{i}");
+        }
+
+        Device {
+          percentage: config.percentage.clamp(0.0, 100.0),
+          charging: config.charging,
+          disconnected_until: None,
+          config,
+        }
+      })
+      .collect();
+
+    Self {
+      simulation: Arc::new(Mutex::new(Simulation {
+        devices,
+        random: Random(config.seed),
+        clock,
+        now: 0,
+        sender: None,
+      })),
+    }
+  }
+
+  /// A simulation whose clock only moves with `advance_to`
+  #[cfg(test)]
+  fn manual(config: &SimulationConfig) -> Self {
+    Self::with_clock(config, Clock::Manual)
+  }
+
+  /// Play out every simulated second up to `seconds`, pushing whatever
+  /// changed along the way
+  #[cfg(test)]
+  fn advance_to(&self, seconds: u64) {
+    self.simulation.lock().unwrap().advance_to(seconds);
+  }
+}
+
+impl crate::source::BatterySource for Simulated {
+  fn name(&self) -> &'static str { "simulation" }
+
+  fn is_connected(&self) -> bool { true }
+
+  fn devices(&mut self) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    let mut simulation = self.simulation.lock().unwrap();
+
+    simulation.advance();
+
+    Ok(crate::source::label_devices(
+      simulation
+        .devices
+        .iter()
+        .filter(|device| device.disconnected_until.is_none())
+        .map(|device| {
+          DeviceInfo::new(
+            &device.config.id,
+            "WIRELESS",
+            &device.config.device_type,
+            &device.config.name,
+          )
+        })
+        .collect(),
+    ))
+  }
+
+  fn battery(&mut self, id: &str) -> crate::source::Result<BatteryState> {
+    let mut simulation = self.simulation.lock().unwrap();
+
+    simulation.advance();
+
+    match simulation
+      .devices
+      .iter()
+      .find(|device| device.config.id == id)
+    {
+      Some(device) if device.disconnected_until.is_none() =>
+        Ok(device.battery_state()),
+      Some(_) => Err(format!("simulated device '{id}' is disconnected").into()),
+      None => Err(format!("no simulated device with id '{id}'").into()),
+    }
+  }
+
+  fn subscribe(&mut self, sender: Sender<Push>) {
+    let simulation = self.simulation.clone();
+
+    simulation.lock().unwrap().sender = Some(sender);
+
+    // Keeps the clock ticking, so that changes are pushed as they happen
+    // instead of whenever the tray polls
+    std::thread::spawn(move || loop {
+      std::thread::sleep(TICK_INTERVAL);
+
+      let mut simulation = simulation.lock().unwrap();
+
+      simulation.advance();
+
+      if simulation.sender.is_none() {
+        break;
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc;
+
+  use super::*;
+  use crate::source::BatterySource;
+
+  const DISCHARGE: [(f64, f64); 3] =
+    [(0.0, 100.0), (3_600.0, 20.0), (4_000.0, 0.0)];
+
+  fn flaky_mouse(seed: u64) -> SimulationConfig {
+    SimulationConfig {
+      seed,
+      speed: 1.0,
+      devices: vec![SimulatedDeviceConfig {
+        id: "mouse".to_string(),
+        discharge: DISCHARGE.to_vec(),
+        disconnects_per_hour: 60.0,
+        disconnect_seconds: 30,
+        charges_per_hour: 30.0,
+        ..SimulatedDeviceConfig::default()
+      }],
+    }
+  }
+
+  /// Everything pushed over the first simulated `seconds` of `config`
+  fn pushes(config: &SimulationConfig, seconds: u64) -> Vec<String> {
+    let mut simulated = Simulated::manual(config);
+    let (sender, pushes) = mpsc::channel();
+
+    simulated.subscribe(sender);
+    simulated.advance_to(seconds);
+
+    pushes.try_iter().map(|push| format!("{push:?}")).collect()
+  }
+
+  #[test]
+  fn plays_out_the_same_way_for_the_same_seed() {
+    let pushes = pushes(&flaky_mouse(7), 4 * 3_600);
+
+    assert!(pushes.iter().any(|push| push.starts_with("BatteryState")));
+    assert!(pushes.iter().any(|push| push == "DeviceState"));
+    assert_eq!(pushes, self::pushes(&flaky_mouse(7), 4 * 3_600));
+    assert_ne!(pushes, self::pushes(&flaky_mouse(8), 4 * 3_600));
+  }
+
+  #[test]
+  fn only_moves_with_the_manual_clock() {
+    let mut simulated = Simulated::manual(&SimulationConfig {
+      devices: vec![SimulatedDeviceConfig {
+        discharge: DISCHARGE.to_vec(),
+        ..SimulatedDeviceConfig::default()
+      }],
+      // Anything real time did would show straight away
+      speed: 3_600.0,
+      ..SimulationConfig::default()
+    });
+
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(simulated.battery("simulated_0").unwrap().percentage(), 100);
+    simulated.advance_to(1_800);
+    assert_eq!(simulated.battery("simulated_0").unwrap().percentage(), 60);
+    simulated.advance_to(3_600);
+    assert_eq!(simulated.battery("simulated_0").unwrap().percentage(), 20);
+  }
+
+  #[test]
+  fn follows_the_segment_a_percentage_falls_in() {
+    let first = -80.0 / 3_600.0;
+    let second = -20.0 / 400.0;
+
+    assert!((slope(&DISCHARGE, 100.0) - first).abs() < f64::EPSILON);
+    assert!((slope(&DISCHARGE, 50.0) - first).abs() < f64::EPSILON);
+    // Where two segments meet, the earlier one wins
+    assert!((slope(&DISCHARGE, 20.0) - first).abs() < f64::EPSILON);
+    assert!((slope(&DISCHARGE, 19.9) - second).abs() < f64::EPSILON);
+    assert!((slope(&DISCHARGE, 0.0) - second).abs() < f64::EPSILON);
+  }
+
+  #[test]
+  fn stays_put_outside_of_the_curve() {
+    let charge = [(0.0, 0.0), (3_600.0, 80.0), (3_600.0, 90.0)];
+
+    assert!((slope(&charge, 40.0) - 80.0 / 3_600.0).abs() < f64::EPSILON);
+    // Segments without any duration go nowhere, rather than dividing by zero
+    assert!(slope(&charge, 85.0).abs() < f64::EPSILON);
+    assert!(slope(&charge, 95.0).abs() < f64::EPSILON);
+    assert!(slope(&[], 50.0).abs() < f64::EPSILON);
+    assert!(slope(&[(0.0, 100.0)], 100.0).abs() < f64::EPSILON);
+  }
+}
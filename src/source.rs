@@ -0,0 +1,533 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+  collections::HashMap,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::Sender,
+    Arc,
+  },
+  time::Duration,
+};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{Config, SourceKind};
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+  pub id: String,
+  #[serde(rename = "connectionType", default)]
+  connection_type: String,
+  #[serde(rename = "deviceType", default)]
+  device_type: String,
+  #[serde(rename = "displayName")]
+  pub display_name: String,
+  /// What the device is called in menus and tooltips
+  ///
+  /// This is the display name, numbered if other devices share it, so that
+  /// two of the same mouse can be told apart.
+  #[serde(skip)]
+  pub label: String,
+}
+
+impl DeviceInfo {
+  pub fn new(
+    id: &str,
+    connection_type: &str,
+    device_type: &str,
+    display_name: &str,
+  ) -> Self {
+    Self {
+      id: id.to_string(),
+      connection_type: connection_type.to_string(),
+      device_type: device_type.to_string(),
+      display_name: display_name.to_string(),
+      label: display_name.to_string(),
+    }
+  }
+
+  pub fn from_device_info(device_info: &Self) -> Self {
+    Self {
+      id: device_info.id.clone(),
+      connection_type: device_info.connection_type.clone(),
+      device_type: device_info.device_type.clone(),
+      display_name: device_info.display_name.clone(),
+      label: device_info.label.clone(),
+    }
+  }
+
+  pub fn connection_type(&self) -> &str { &self.connection_type }
+
+  pub fn device_type(&self) -> &str { &self.device_type }
+}
+
+/// The battery state of a device, as reported by a battery source
+///
+/// Only the percentage is always present. Anything else that a battery source
+/// leaves out for a device falls back to not charging, not low, not critical,
+/// and no known voltage.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct BatteryState {
+  percentage: u64,
+  #[serde(default)]
+  charging: bool,
+  millivolts: Option<u64>,
+  #[serde(default)]
+  critical: bool,
+  #[serde(default)]
+  low: bool,
+}
+
+impl BatteryState {
+  pub const fn new(
+    percentage: u64,
+    charging: bool,
+    millivolts: Option<u64>,
+    critical: bool,
+    low: bool,
+  ) -> Self {
+    Self {
+      percentage,
+      charging,
+      millivolts,
+      critical,
+      low,
+    }
+  }
+
+  pub const fn percentage(&self) -> u64 { self.percentage }
+
+  pub const fn is_charging(&self) -> bool { self.charging }
+
+  pub const fn millivolts(&self) -> Option<u64> { self.millivolts }
+
+  pub const fn is_critical(&self) -> bool { self.critical }
+
+  pub const fn is_low(&self) -> bool { self.low }
+}
+
+/// A battery state change pushed by a battery source
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatteryStateChange {
+  #[serde(rename = "deviceId")]
+  device_id: String,
+  #[serde(flatten)]
+  state: BatteryState,
+}
+
+impl BatteryStateChange {
+  pub fn new(device_id: &str, state: BatteryState) -> Self {
+    Self {
+      device_id: device_id.to_string(),
+      state,
+    }
+  }
+
+  pub fn device_id(&self) -> &str { &self.device_id }
+
+  pub const fn state(&self) -> &BatteryState { &self.state }
+}
+
+/// Something a battery source pushed without being asked for
+#[derive(Debug)]
+pub enum Push {
+  /// A device's battery state changed
+  BatteryState(BatteryStateChange),
+  /// A device was turned on, turned off, plugged in, or unplugged
+  DeviceState,
+}
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAXIMUM_BACKOFF: Duration = Duration::from_secs(32);
+
+/// Exponentially growing delays between attempts to reach a battery source, so
+/// that a restarting one isn't hammered with connection attempts
+pub struct Backoff {
+  delay: Duration,
+}
+
+impl Default for Backoff {
+  fn default() -> Self {
+    Self {
+      delay: INITIAL_BACKOFF,
+    }
+  }
+}
+
+impl Backoff {
+  /// Take the current delay, doubling it for the next attempt
+  fn next_delay(&mut self) -> Duration {
+    let delay = self.delay;
+
+    self.delay = (self.delay * 2).min(MAXIMUM_BACKOFF);
+
+    delay
+  }
+
+  /// Sleep for the current delay, then double it for the next attempt
+  pub fn wait(&mut self) {
+    let delay = self.next_delay();
+
+    trace!("backing off for {}ms", delay.as_millis());
+    std::thread::sleep(delay);
+  }
+}
+
+/// Whatever went wrong while reading from a battery source
+///
+/// Every source has its own errors, so they're only ever logged or shown to the
+/// user. Whether the source could be reached at all is told apart by
+/// `BatterySource::is_connected` instead.
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A request a battery source gave up on, without anything being wrong with
+/// the source itself
+///
+/// Sources report these as the cause of their own errors, so that the tray can
+/// tell them apart from any other failure, whichever source it's reading from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupted {
+  /// The source didn't reply in time, most likely because it's wedged
+  Timeout,
+  /// The request was cancelled before the source replied
+  Cancelled,
+}
+
+impl Interrupted {
+  /// Find out whether an error, or anything that caused it, is an interrupted
+  /// request
+  pub fn of(error: &(dyn std::error::Error + 'static)) -> Option<Self> {
+    std::iter::successors(Some(error), |error| error.source())
+      .find_map(|error| error.downcast_ref::<Self>().copied())
+  }
+}
+
+impl std::fmt::Display for Interrupted {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Timeout => write!(f, "battery source didn't reply in time"),
+      Self::Cancelled => write!(f, "request to battery source was cancelled"),
+    }
+  }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Keeps count of a battery source's requests, so that cancelling the ones in
+/// flight never cancels any started after
+#[derive(Default)]
+pub struct InFlight {
+  /// The number of the last request started
+  started: AtomicU64,
+  /// Every request numbered up to this one is cancelled
+  cancelled: AtomicU64,
+}
+
+impl InFlight {
+  /// Start a request, getting the number to check for its cancellation with
+  pub fn start(&self) -> u64 { self.started.fetch_add(1, Ordering::SeqCst) + 1 }
+
+  /// Whether the request numbered `request` has been cancelled
+  pub fn is_cancelled(&self, request: u64) -> bool {
+    self.cancelled.load(Ordering::SeqCst) >= request
+  }
+
+  fn cancel(&self) {
+    self
+      .cancelled
+      .fetch_max(self.started.load(Ordering::SeqCst), Ordering::SeqCst);
+  }
+}
+
+/// Cancels whatever requests a battery source has in flight, from any thread
+///
+/// Only G HUB's requests can be cancelled, since it's the only source which
+/// can keep elem waiting for long. The others answer from memory (the
+/// simulation and the external command), from the kernel (sysfs, and HID++
+/// within a second), or from the local D-Bus, which gives up on its own. So
+/// cancelling them does nothing.
+#[derive(Clone, Default)]
+pub struct Canceller {
+  requests: Vec<Arc<InFlight>>,
+}
+
+impl Canceller {
+  pub fn new(requests: Arc<InFlight>) -> Self {
+    Self {
+      requests: vec![requests],
+    }
+  }
+
+  /// Cancel everything `other` cancels too
+  fn merge(mut self, other: Self) -> Self {
+    self.requests.extend(other.requests);
+
+    self
+  }
+
+  /// Cancel every request in flight, leaving any started later alone
+  pub fn cancel(&self) {
+    for requests in &self.requests {
+      requests.cancel();
+    }
+  }
+}
+
+/// Somewhere devices and their battery levels come from, like Logitech G HUB
+///
+/// The tray only ever talks to a battery source, so adding a new one doesn't
+/// involve touching the tray at all.
+pub trait BatterySource: Send {
+  /// What the source is called in log messages and tooltips
+  fn name(&self) -> &'static str;
+
+  /// Whether the source could be reached, as of the last request
+  fn is_connected(&self) -> bool;
+
+  /// List the devices this source knows about, keyed by their ID
+  fn devices(&mut self) -> Result<HashMap<String, DeviceInfo>>;
+
+  /// Read the battery state of a device by its ID
+  fn battery(&mut self, id: &str) -> Result<BatteryState>;
+
+  /// Start forwarding battery and device state changes to `sender` in the
+  /// background, for as long as `sender` has a receiver
+  ///
+  /// Sources which can't push anything do nothing, and are polled instead.
+  fn subscribe(&mut self, sender: Sender<Push>);
+
+  /// Get a handle which cancels this source's in-flight requests
+  fn canceller(&self) -> Canceller { Canceller::default() }
+}
+
+/// Key devices by their ID, labelling each one with its display name, numbered
+/// if other devices share it so that two of the same mouse can be told apart
+pub fn label_devices(
+  mut devices: Vec<DeviceInfo>,
+) -> HashMap<String, DeviceInfo> {
+  let mut display_name_counts = HashMap::new();
+  let mut labelled = HashMap::new();
+
+  // Sorting by ID so that the same devices are numbered the same way every
+  // time they're listed
+  devices.sort_by(|a, b| a.id.cmp(&b.id));
+
+  for mut device in devices {
+    let count = display_name_counts
+      .entry(device.display_name.clone())
+      .or_insert(0);
+
+    *count += 1;
+    device.label = if *count == 1 {
+      device.display_name.clone()
+    } else {
+      format!("{} ({count})", device.display_name)
+    };
+
+    labelled.insert(device.id.clone(), device);
+  }
+
+  labelled
+}
+
+/// Several battery sources read from as if they were one
+///
+/// Devices from every source are listed together, and reading a battery is
+/// routed to whichever source listed the device.
+pub struct Combined {
+  sources: Vec<Box<dyn BatterySource>>,
+  /// Which source each device came from, as of the last device listing
+  owners: HashMap<String, usize>,
+}
+
+impl Combined {
+  pub fn new(sources: Vec<Box<dyn BatterySource>>) -> Self {
+    Self {
+      sources,
+      owners: HashMap::new(),
+    }
+  }
+}
+
+impl BatterySource for Combined {
+  fn name(&self) -> &'static str { "battery sources" }
+
+  /// A single reachable source is enough to keep going
+  fn is_connected(&self) -> bool {
+    self.sources.iter().any(|source| source.is_connected())
+  }
+
+  fn devices(&mut self) -> Result<HashMap<String, DeviceInfo>> {
+    let mut devices = vec![];
+    let mut listed = false;
+    let mut last_error = None;
+
+    self.owners.clear();
+
+    for (i, source) in self.sources.iter_mut().enumerate() {
+      match source.devices() {
+        Ok(source_devices) => {
+          listed = true;
+
+          for (id, device) in source_devices {
+            self.owners.insert(id, i);
+            devices.push(device);
+          }
+        }
+        Err(e) => {
+          warn!("failed to list devices from {}: {}", source.name(), e);
+
+          last_error = Some(e);
+        }
+      }
+    }
+
+    // Only give up if not a single source could list its devices
+    match last_error {
+      Some(e) if !listed => Err(e),
+      // Relabelling, because devices from different sources can share a
+      // display name too
+      _ => Ok(label_devices(devices)),
+    }
+  }
+
+  fn battery(&mut self, id: &str) -> Result<BatteryState> {
+    if !self.owners.contains_key(id) {
+      self.devices()?;
+    }
+
+    match self.owners.get(id) {
+      Some(&i) => self.sources[i].battery(id),
+      None => Err(format!("no device with id '{id}'").into()),
+    }
+  }
+
+  fn subscribe(&mut self, sender: Sender<Push>) {
+    for source in &mut self.sources {
+      source.subscribe(sender.clone());
+    }
+  }
+
+  fn canceller(&self) -> Canceller {
+    self
+      .sources
+      .iter()
+      .fold(Canceller::default(), |canceller, source| {
+        canceller.merge(source.canceller())
+      })
+  }
+}
+
+/// Build the battery source, or sources, that the configuration asks for
+///
+/// Sources which can't run here are skipped, but a source which is configured
+/// wrong is refused, since it's a mistake to fix rather than to work around.
+pub fn from_config(config: &Config) -> Result<Box<dyn BatterySource>> {
+  let mut sources = config
+    .sources
+    .iter()
+    .filter_map(|kind| -> Option<Result<Box<dyn BatterySource>>> {
+      match kind {
+        SourceKind::Logitech => Some(
+          crate::logitech::Client::new(&config.logitech)
+            .map(|client| Box::new(client) as Box<dyn BatterySource>)
+            .map_err(Into::into),
+        ),
+        SourceKind::Sysfs => Some(Ok(Box::new(
+          crate::sysfs::PowerSupplies::new(&config.sysfs.root),
+        ))),
+        SourceKind::Simulation => Some(Ok(Box::new(
+          crate::simulation::Simulated::new(&config.simulation),
+        ))),
+        SourceKind::Hidpp =>
+          Some(Ok(Box::new(crate::hidpp::HidPlusPlus::new(&config.hidpp)))),
+        SourceKind::Command => config.command.program.as_ref().map_or_else(
+          || {
+            warn!(
+              "no program is configured for the command source, skipping it"
+            );
+
+            None
+          },
+          |program| -> Option<Result<Box<dyn BatterySource>>> {
+            Some(Ok(Box::new(crate::command::ExternalCommand::new(
+              program,
+              &config.command.args,
+            ))))
+          },
+        ),
+        #[cfg(target_os = "linux")]
+        SourceKind::UPower => Some(Ok(Box::new(crate::upower::UPower::new(
+          config.upower.bus.clone(),
+        )))),
+        #[cfg(not(target_os = "linux"))]
+        SourceKind::UPower => {
+          warn!("upower is only available on linux, skipping it");
+
+          None
+        }
+        #[cfg(target_os = "linux")]
+        SourceKind::BlueZ => Some(Ok(Box::new(crate::bluez::BlueZ::new(
+          config.bluez.bus.clone(),
+        )))),
+        #[cfg(not(target_os = "linux"))]
+        SourceKind::BlueZ => {
+          warn!("bluez is only available on linux, skipping it");
+
+          None
+        }
+        #[cfg(target_os = "linux")]
+        SourceKind::KdeConnect => Some(Ok(Box::new(
+          crate::kdeconnect::KdeConnect::new(config.kdeconnect.bus.clone()),
+        ))),
+        #[cfg(not(target_os = "linux"))]
+        SourceKind::KdeConnect => {
+          warn!("kde connect is only available on linux, skipping it");
+
+          None
+        }
+      }
+    })
+    .collect::<Result<Vec<_>>>()?;
+
+  Ok(if sources.len() == 1 {
+    // We can always pop the only source because we just made sure there is
+    // one.
+    sources.pop().unwrap()
+  } else {
+    Box::new(Combined::new(sources))
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn backoff_doubles_up_to_the_maximum() {
+    let mut backoff = Backoff::default();
+    let delays = std::iter::repeat_with(|| backoff.next_delay().as_secs())
+      .take(8)
+      .collect::<Vec<_>>();
+
+    assert_eq!(delays, [1, 2, 4, 8, 16, 32, 32, 32]);
+  }
+}
@@ -0,0 +1,269 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::mpsc::Sender,
+};
+
+use crate::source::{BatteryState, DeviceInfo, Push};
+
+/// Peripheral batteries exposed by the Linux kernel under
+/// `/sys/class/power_supply`, like the ones the `hid-logitech-hidpp` driver
+/// reports
+///
+/// Each power supply's directory name is used as its device ID.
+pub struct PowerSupplies {
+  root: PathBuf,
+}
+
+impl PowerSupplies {
+  /// Read power supplies from `root` instead of the real sysfs, which is
+  /// useful for pointing elem at a fake directory tree
+  pub fn new(root: &Path) -> Self {
+    Self {
+      root: root.to_path_buf(),
+    }
+  }
+
+  /// Read a single attribute of a power supply, if it has it
+  fn attribute(&self, id: &str, name: &str) -> Option<String> {
+    std::fs::read_to_string(self.root.join(id).join(name))
+      .ok()
+      .map(|value| value.trim().to_string())
+  }
+
+  /// Whether a power supply is a connected peripheral's battery, and not the
+  /// system's own battery or a charger
+  fn is_peripheral_battery(&self, id: &str) -> bool {
+    self.attribute(id, "type").as_deref() == Some("Battery")
+      && self.attribute(id, "scope").as_deref() == Some("Device")
+      // Peripherals which have been turned off can stick around as offline
+      && self.attribute(id, "online").as_deref() != Some("0")
+  }
+}
+
+impl crate::source::BatterySource for PowerSupplies {
+  fn name(&self) -> &'static str { "sysfs" }
+
+  fn is_connected(&self) -> bool { self.root.is_dir() }
+
+  fn devices(&mut self) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    let mut devices = vec![];
+
+    for entry in std::fs::read_dir(&self.root)? {
+      let id = entry?.file_name().to_string_lossy().to_string();
+
+      if !self.is_peripheral_battery(&id) {
+        continue;
+      }
+
+      devices.push(DeviceInfo::new(
+        &id,
+        "WIRELESS",
+        "UNKNOWN",
+        &self
+          .attribute(&id, "model_name")
+          .unwrap_or_else(|| id.clone()),
+      ));
+    }
+
+    Ok(crate::source::label_devices(devices))
+  }
+
+  fn battery(&mut self, id: &str) -> crate::source::Result<BatteryState> {
+    if !self.is_peripheral_battery(id) {
+      return Err(format!("no peripheral battery named '{id}'").into());
+    }
+
+    let capacity_level = self.attribute(id, "capacity_level");
+    // Not every device reports an exact percentage, so the capacity level is
+    // used as a rough estimate for those that don't.
+    let percentage = match self.attribute(id, "capacity") {
+      Some(capacity) => capacity.parse()?,
+      None => match capacity_level.as_deref() {
+        Some("Full") => 100,
+        Some("High") => 75,
+        Some("Normal") => 50,
+        Some("Low") => 20,
+        Some("Critical") => 5,
+        _ => return Err(format!("'{id}' doesn't report its capacity").into()),
+      },
+    };
+
+    Ok(BatteryState::new(
+      percentage,
+      self.attribute(id, "status").as_deref() == Some("Charging"),
+      // sysfs reports voltages in microvolts
+      self
+        .attribute(id, "voltage_now")
+        .and_then(|microvolts| microvolts.parse::<u64>().ok())
+        .map(|microvolts| microvolts / 1000),
+      capacity_level.as_deref() == Some("Critical"),
+      capacity_level.as_deref() == Some("Low"),
+    ))
+  }
+
+  /// sysfs can't be watched for battery changes, so it's only ever polled
+  fn subscribe(&mut self, _sender: Sender<Push>) {}
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::source::BatterySource;
+
+  /// A fake `/sys/class/power_supply`, removed again once dropped
+  struct Tree(PathBuf);
+
+  impl Tree {
+    fn new(name: &str) -> Self {
+      let root = std::env::temp_dir()
+        .join(format!("elem-sysfs-{}-{name}", std::process::id()));
+
+      std::fs::remove_dir_all(&root).ok();
+      std::fs::create_dir_all(&root).unwrap();
+
+      Self(root)
+    }
+
+    /// Add a power supply with the given attributes
+    fn supply(&self, id: &str, attributes: &[(&str, &str)]) -> &Self {
+      let directory = self.0.join(id);
+
+      std::fs::create_dir_all(&directory).unwrap();
+
+      for (name, value) in attributes {
+        // sysfs attributes end in a newline
+        std::fs::write(directory.join(name), format!("{value}\n")).unwrap();
+      }
+
+      self
+    }
+
+    /// Add a peripheral's battery with the given attributes on top
+    fn peripheral(&self, id: &str, attributes: &[(&str, &str)]) -> &Self {
+      self.supply(
+        id,
+        &[&[("type", "Battery"), ("scope", "Device")], attributes].concat(),
+      )
+    }
+
+    fn source(&self) -> PowerSupplies { PowerSupplies::new(&self.0) }
+  }
+
+  impl Drop for Tree {
+    fn drop(&mut self) { std::fs::remove_dir_all(&self.0).ok(); }
+  }
+
+  #[test]
+  fn lists_only_online_peripheral_batteries() {
+    let tree = Tree::new("lists");
+
+    tree
+      .peripheral(
+        "hidpp_battery_0",
+        &[("online", "1"), ("model_name", "G305")],
+      )
+      .peripheral("hidpp_battery_1", &[("online", "0")])
+      .peripheral("hid-00:11:22:33:44:55-battery", &[])
+      .supply("BAT0", &[("type", "Battery"), ("scope", "System")])
+      .supply("AC", &[("type", "Mains")]);
+
+    let devices = tree.source().devices().unwrap();
+    let mut ids = devices.keys().map(String::as_str).collect::<Vec<_>>();
+
+    ids.sort_unstable();
+
+    assert_eq!(ids, ["hid-00:11:22:33:44:55-battery", "hidpp_battery_0"]);
+    assert_eq!(devices["hidpp_battery_0"].label, "G305");
+    // Without a model name, the directory name is all there is to go by
+    assert_eq!(
+      devices["hid-00:11:22:33:44:55-battery"].label,
+      "hid-00:11:22:33:44:55-battery"
+    );
+  }
+
+  #[test]
+  fn reads_the_battery_state() {
+    let tree = Tree::new("reads");
+
+    tree.peripheral(
+      "hidpp_battery_0",
+      &[
+        ("capacity", "57"),
+        ("capacity_level", "Normal"),
+        ("status", "Charging"),
+        ("voltage_now", "3912500"),
+      ],
+    );
+
+    let state = tree.source().battery("hidpp_battery_0").unwrap();
+
+    assert_eq!(state.percentage(), 57);
+    assert!(state.is_charging());
+    assert_eq!(state.millivolts(), Some(3912));
+    assert!(!state.is_low() && !state.is_critical());
+  }
+
+  #[test]
+  fn falls_back_on_the_capacity_level() {
+    let tree = Tree::new("falls_back");
+
+    for (level, percentage) in [
+      ("Full", 100),
+      ("High", 75),
+      ("Normal", 50),
+      ("Low", 20),
+      ("Critical", 5),
+    ] {
+      tree.peripheral(
+        level,
+        &[("capacity_level", level), ("status", "Discharging")],
+      );
+
+      let state = tree.source().battery(level).unwrap();
+
+      assert_eq!(state.percentage(), percentage, "{level}");
+      assert!(!state.is_charging());
+      assert_eq!(state.millivolts(), None);
+      assert_eq!(state.is_low(), level == "Low");
+      assert_eq!(state.is_critical(), level == "Critical");
+    }
+
+    tree.peripheral("Unknown", &[("capacity_level", "Unknown")]);
+
+    assert!(tree.source().battery("Unknown").is_err());
+  }
+
+  #[test]
+  fn refuses_anything_but_a_peripheral_battery() {
+    let tree = Tree::new("refuses");
+
+    tree
+      .supply(
+        "BAT0",
+        &[("type", "Battery"), ("scope", "System"), ("capacity", "80")],
+      )
+      .peripheral("hidpp_battery_0", &[("online", "0"), ("capacity", "80")]);
+
+    assert!(tree.source().battery("BAT0").is_err());
+    assert!(tree.source().battery("hidpp_battery_0").is_err());
+    assert!(tree.source().battery("missing").is_err());
+  }
+}
@@ -16,36 +16,86 @@
 // SPDX-License-Identifier: GPL-3.0-only
 
 use std::{
-  ffi::OsStr,
-  iter::once,
-  os::windows::ffi::OsStrExt,
-  sync::{Arc, Mutex},
+  collections::HashMap,
+  sync::{
+    mpsc::{Receiver, RecvTimeoutError},
+    Arc, Mutex,
+  },
 };
+#[cfg(windows)]
+use std::{ffi::OsStr, iter::once, os::windows::ffi::OsStrExt};
 
 use tao::{
-  event::Event, event_loop::ControlFlow, menu, menu::CustomMenuItem,
-  system_tray, system_tray::Icon,
+  event::Event,
+  event_loop::{ControlFlow, EventLoop, EventLoopProxy},
+  menu,
+  menu::CustomMenuItem,
+  system_tray,
+  system_tray::Icon,
 };
+#[cfg(windows)]
 use winapi::um::{wincon::GetConsoleWindow, winuser, winuser::ShowWindow};
 
+use crate::{
+  ascii_art::{Glyph, Renderer},
+  selection::Selection,
+  source::Interrupted,
+};
+
 const DEFAULT_UPDATE_FREQUENCY: u64 = 60000;
 
+/// A device in the devices menu
+///
+/// Menu items can only be touched from the event loop on some platforms, so
+/// these never leave it.
+struct DeviceItem {
+  /// The ID of the device, as given by the battery source
+  id: String,
+  label: String,
+  item: CustomMenuItem,
+}
+
 struct TrayInner {
-  devices: Vec<CustomMenuItem>,
-  selected_device_display_name: Option<String>,
+  selection: Selection,
   update_frequency: u64,
 }
 
+/// Everything the watchman waits on
+enum Watch {
+  /// A battery or device state change pushed by the battery source
+  Push(crate::source::Push),
+  /// A device was selected from the devices menu, so its battery level is
+  /// fetched next
+  Selected,
+}
+
+/// Events sent to the event loop from background threads
+enum UserEvent {
+  /// A fresh device list, either because the battery source is reachable again
+  /// or because devices were added or removed
+  Devices(HashMap<String, crate::source::DeviceInfo>),
+  /// An icon and tooltip to show, since the system tray can only be touched
+  /// from the event loop on some platforms
+  Status(Icon, String),
+}
+
+type Source = Mutex<Box<dyn crate::source::BatterySource>>;
+
 pub struct Tray {
   inner: Arc<Mutex<TrayInner>>,
+  source: Arc<Source>,
+  renderer: Arc<Renderer>,
 }
 
 impl Tray {
-  pub fn new(update_frequency: Option<String>) -> Self {
+  pub fn new(
+    update_frequency: Option<String>,
+    source: Box<dyn crate::source::BatterySource>,
+    renderer: Renderer,
+  ) -> Self {
     Self {
       inner: Arc::new(Mutex::new(TrayInner {
-        devices: vec![],
-        selected_device_display_name: None,
+        selection: Selection::default(),
         update_frequency: {
           update_frequency.map_or_else(
             || {
@@ -77,218 +127,545 @@ impl Tray {
           )
         },
       })),
+      source: Arc::new(Mutex::new(source)),
+      renderer: Arc::new(renderer),
     }
   }
 
-  /// Force an icon by bypassing the device state check.
-  ///
-  /// Useful for displaying informational icons
-  fn force_icon(code: &str) -> Icon {
-    trace!("building forced icon '{}'", code);
-
-    let image = image::load_from_memory(&crate::ascii_art::number_to_image(
-      // This will never fail because we as the author provide a code that is
-      // always a number in string form.
-      code.parse().unwrap(),
-    ))
-    .unwrap_or_else(|_| quit(&format!("failed to load forced icon '{code}'")))
-    .into_rgba8();
+  /// Build a tray icon compatible icon from a glyph
+  fn glyph_icon(renderer: &Renderer, glyph: Glyph) -> Icon {
+    trace!("building icon for {:?}", glyph);
+
+    let image = image::load_from_memory(&renderer.render(glyph))
+      .unwrap_or_else(|_| quit(&format!("failed to load icon for {glyph:?}")))
+      .into_rgba8();
     let (width, height) = image.dimensions();
     let icon =
       Icon::from_rgba(image.into_raw(), width, height).unwrap_or_else(|_| {
-        quit(&format!("failed to convert forced icon '{code}' to rgba"))
+        quit(&format!("failed to convert icon for {glyph:?} to rgba"))
       });
 
-    trace!("built forced icon '{}'", code);
+    trace!("built icon for {:?}", glyph);
 
     icon
   }
 
-  /// Create a tray icon compatible icon from a devices battery level
-  fn icon(selected_device_display_name: &Option<String>) -> Icon {
-    trace!(
-      "building icon for display name '{:?}'",
-      selected_device_display_name
-    );
+  /// The glyph showing a devices battery level
+  fn battery_glyph(battery_state: &crate::source::BatteryState) -> Glyph {
+    // Battery sources never report more than one hundred percent, but they're
+    // clamped just in case
+    let percentage =
+      u8::try_from(battery_state.percentage().min(100)).unwrap_or(100);
 
-    let image = image::load_from_memory(&if selected_device_display_name
-      == &Some("43770".to_string())
-      || selected_device_display_name == &Some("Dummy (Debug)".to_string())
-    {
-      crate::ascii_art::number_to_image(43770)
+    if battery_state.is_charging() {
+      Glyph::Charging(percentage)
     } else {
-      crate::ascii_art::number_to_image(
-        crate::logitech::device(
-          &selected_device_display_name
-            .clone()
-            .unwrap_or_else(|| "1337".to_string()),
-        )
-        .payload()
-        .percentage(),
-      )
-    })
-    .unwrap_or_else(|_| {
-      quit(&format!(
-        "failed to load icon for display name '{:?}'",
-        selected_device_display_name
-      ))
-    })
-    .into_rgba8();
-    let (width, height) = image.dimensions();
-    let icon =
-      Icon::from_rgba(image.into_raw(), width, height).unwrap_or_else(|_| {
-        quit(&format!(
-          "failed to convert icon for display name '{:?}' to rgba",
-          selected_device_display_name
-        ))
-      });
+      Glyph::Percent(percentage)
+    }
+  }
 
-    trace!(
-      "built icon for display name '{:?}'",
-      selected_device_display_name
-    );
+  /// Describe a devices battery state for the tray icon tooltip
+  fn tooltip(
+    label: &str,
+    battery_state: &crate::source::BatteryState,
+  ) -> String {
+    // Charging takes precedence because a device that's plugged in isn't about
+    // to die, no matter how low it is.
+    let status = if battery_state.is_charging() {
+      ", charging"
+    } else if battery_state.is_critical() {
+      ", critical"
+    } else if battery_state.is_low() {
+      ", low"
+    } else {
+      ""
+    };
+    let voltage = battery_state
+      .millivolts()
+      .map(|millivolts| format!(", {millivolts}mV"))
+      .unwrap_or_default();
+
+    format!(
+      "elem ({label}: {}%{status}{voltage})",
+      battery_state.percentage()
+    )
+  }
 
-    icon
+  /// Create a tray icon compatible icon from a devices battery level, along
+  /// with a tooltip describing its battery state
+  ///
+  /// Nothing is returned if fetching the battery level was cancelled, since
+  /// whatever cancelled it is about to show something newer anyway.
+  fn icon(
+    source: &Source,
+    renderer: &Renderer,
+    selected_device_id: &str,
+    label: &str,
+  ) -> Option<(Icon, String)> {
+    trace!("building icon for device '{}'", selected_device_id);
+
+    let mut source = source.lock().unwrap();
+    let (glyph, tooltip) = match source.battery(selected_device_id) {
+      Ok(battery_state) => (
+        Self::battery_glyph(&battery_state),
+        Self::tooltip(label, &battery_state),
+      ),
+      Err(e) => {
+        let interrupted = Interrupted::of(e.as_ref());
+
+        if interrupted == Some(Interrupted::Cancelled) {
+          debug!(
+            "cancelled fetching battery level for device '{}'",
+            selected_device_id
+          );
+
+          return None;
+        }
+
+        warn!(
+          "failed to fetch battery level for device '{}': {}",
+          selected_device_id, e
+        );
+
+        // A battery source which doesn't reply in time is as good as
+        // unreachable
+        if interrupted == Some(Interrupted::Timeout) {
+          (Glyph::Disconnected, format!("elem ({label}: timed out)"))
+        } else if source.is_connected() {
+          (Glyph::Error, format!("elem ({label}: unavailable)"))
+        } else {
+          (Glyph::Disconnected, format!("elem ({label}: disconnected)"))
+        }
+      }
+    };
+
+    drop(source);
+
+    let icon = Self::glyph_icon(renderer, glyph);
+
+    trace!("built icon for device '{}'", selected_device_id);
+
+    Some((icon, tooltip))
   }
 
-  /// Checks and update the battery level of non-dummy devices
+  /// Checks and update the battery level of the selected device, and keeps the
+  /// devices menu in sync with the devices that are actually around
+  ///
+  /// Battery and device state changes pushed by the battery source are
+  /// applied as soon as they arrive, and so are device selections. The update
+  /// frequency is only used as a fallback to poll the battery source when
+  /// nothing has happened for that long.
   fn watchman(
     icon_self: &Arc<Mutex<TrayInner>>,
-    system_tray_updater: &Arc<Mutex<system_tray::SystemTray>>,
+    source: &Source,
+    renderer: &Renderer,
+    watches: &Receiver<Watch>,
+    proxy: &EventLoopProxy<UserEvent>,
   ) {
     loop {
-      std::thread::sleep(std::time::Duration::from_millis(
+      // The battery source has gone away, so nothing can be updated until it's
+      // back
+      if !source.lock().unwrap().is_connected() {
+        Self::reconnect(icon_self, source, renderer, proxy);
+        Self::update(icon_self, source, renderer, proxy);
+      }
+
+      let update_frequency = std::time::Duration::from_millis(
         icon_self.lock().unwrap().update_frequency,
-      ));
-
-      trace!("checking for system tray icon update");
-
-      // Only refresh the tray icon (battery level) if the device is not a dummy
-      // device
-      if icon_self.lock().unwrap().selected_device_display_name
-        != Some("Dummy (Debug)".to_string())
-      {
-        // "80085" is the internal code for ellipsis. An ellipsis is displayed
-        // while the battery level is being fetched.
-        system_tray_updater
-          .lock()
-          .unwrap()
-          .set_icon(Self::force_icon("80085"));
-        system_tray_updater.lock().unwrap().set_tooltip(&format!(
-          "elem (updating {} from watchman)",
-          &icon_self
-            .lock()
-            .unwrap()
-            .selected_device_display_name
-            .clone()
-            .unwrap_or_else(|| "Dummy (Debug)".to_string())
-        ));
-
-        trace!("updating system tray icon from watchman");
-
-        let icon = Self::icon(&Some(
-          icon_self
-            .lock()
-            .unwrap()
-            .selected_device_display_name
-            .clone()
-            .unwrap_or_else(|| "Dummy (Debug)".to_string()),
-        ));
-
-        system_tray_updater.lock().unwrap().set_tooltip(&format!(
-          "elem ({})",
-          icon_self
-            .lock()
-            .unwrap()
-            .selected_device_display_name
-            .clone()
-            .unwrap_or_else(|| "Dummy (Debug)".to_string()),
-        ));
-        system_tray_updater.lock().unwrap().set_icon(icon);
-        trace!("updated system tray icon",);
+      );
+
+      match watches.recv_timeout(update_frequency) {
+        Ok(Watch::Push(crate::source::Push::BatteryState(change))) => {
+          let (selected_device_id, label) =
+            icon_self.lock().unwrap().selection.selected_device();
+
+          // Only the selected device's battery level is displayed, so pushes
+          // for any other device are ignored.
+          if selected_device_id.as_deref() == Some(change.device_id()) {
+            trace!("updating system tray icon from battery state change");
+            Self::show_status(
+              proxy,
+              Self::glyph_icon(renderer, Self::battery_glyph(change.state())),
+              Self::tooltip(&label, change.state()),
+            );
+          }
+
+          continue;
+        }
+        Ok(Watch::Push(crate::source::Push::DeviceState)) => {
+          if Self::refresh_devices(icon_self, source, proxy) {
+            Self::update(icon_self, source, renderer, proxy);
+          }
+
+          continue;
+        }
+        Ok(Watch::Selected) => {
+          Self::update(icon_self, source, renderer, proxy);
+
+          continue;
+        }
+        // The event loop has gone away, so there's nothing left to update
+        Err(RecvTimeoutError::Disconnected) => return,
+        Err(RecvTimeoutError::Timeout) => {}
       }
+
+      trace!("checking for device list and system tray icon update");
+
+      // Not every battery source pushes device changes, so the device list is
+      // polled too
+      Self::refresh_devices(icon_self, source, proxy);
+      Self::update(icon_self, source, renderer, proxy);
     }
   }
 
-  /// Run the tray icon and event loop
-  #[allow(clippy::too_many_lines)]
-  pub fn run(&mut self) {
-    let local_self = self.inner.clone();
-    // Grab all wireless devices
-    let devices = crate::logitech::wireless_devices();
-    // Set up the event loop and tray icon-related stuff
-    let event_loop = tao::event_loop::EventLoop::new();
-    let main_tray_id = tao::TrayId::new("main-tray");
+  /// Fetch the selected device's battery level and show it
+  fn update(
+    icon_self: &Mutex<TrayInner>,
+    source: &Source,
+    renderer: &Renderer,
+    proxy: &EventLoopProxy<UserEvent>,
+  ) {
+    let (selected_device_id, label) =
+      icon_self.lock().unwrap().selection.selected_device();
+    // An empty device list leaves nothing to fetch, which isn't the same as
+    // the battery source being gone
+    let Some(selected_device_id) = selected_device_id else {
+      Self::show_status(
+        proxy,
+        Self::glyph_icon(renderer, Glyph::Disconnected),
+        Self::no_device_tooltip(source),
+      );
+
+      return;
+    };
+
+    // An ellipsis is displayed while the battery level is being fetched
+    Self::show_status(
+      proxy,
+      Self::glyph_icon(renderer, Glyph::Loading),
+      format!("elem (updating {label} from watchman)"),
+    );
+
+    trace!("updating system tray icon from watchman");
+
+    if let Some((icon, tooltip)) =
+      Self::icon(source, renderer, &selected_device_id, &label)
+    {
+      Self::show_status(proxy, icon, tooltip);
+      trace!("updated system tray icon",);
+    }
+  }
+
+  /// Describe why no device is selected, telling a battery source which can't
+  /// be reached apart from one which just has no devices
+  fn no_device_tooltip(source: &Source) -> String {
+    let name = source.lock().unwrap().name();
+
+    if source.lock().unwrap().is_connected() {
+      format!("elem (no devices on {name})")
+    } else {
+      format!("elem (disconnected from {name})")
+    }
+  }
+
+  /// Hand an icon and tooltip over to the event loop to show
+  fn show_status(
+    proxy: &EventLoopProxy<UserEvent>,
+    icon: Icon,
+    tooltip: String,
+  ) {
+    if proxy.send_event(UserEvent::Status(icon, tooltip)).is_err() {
+      warn!("event loop closed before system tray icon could be updated");
+    }
+  }
+
+  /// Re-list the devices, handing them over to the event loop if any were
+  /// added, removed, or renamed since the devices menu was last built
+  ///
+  /// Whether the device list changed is returned, since the selected device
+  /// may have changed along with it.
+  fn refresh_devices(
+    icon_self: &Mutex<TrayInner>,
+    source: &Source,
+    proxy: &EventLoopProxy<UserEvent>,
+  ) -> bool {
+    let devices = source.lock().unwrap().devices();
+
+    match devices {
+      Ok(devices) => {
+        if icon_self.lock().unwrap().selection.lists_devices(&devices) {
+          return false;
+        }
+
+        debug!("device list changed, rebuilding devices menu");
+        Self::hand_over_devices(icon_self, devices, proxy);
+
+        true
+      }
+      Err(e) => {
+        warn!("failed to refresh device list: {}", e);
+
+        false
+      }
+    }
+  }
+
+  /// Select from a fresh device list, then hand it over to the event loop to
+  /// rebuild the devices menu from
+  fn hand_over_devices(
+    icon_self: &Mutex<TrayInner>,
+    devices: HashMap<String, crate::source::DeviceInfo>,
+    proxy: &EventLoopProxy<UserEvent>,
+  ) {
+    let mut inner = icon_self.lock().unwrap();
+    let (previously_selected_device_id, _) = inner.selection.selected_device();
+
+    inner.selection.adopt(&devices);
+
+    let (selected_device_id, label) = inner.selection.selected_device();
+
+    drop(inner);
+
+    if previously_selected_device_id.is_some()
+      && selected_device_id != previously_selected_device_id
+    {
+      info!(
+        "previously selected device '{}' went away, selected '{}' instead",
+        previously_selected_device_id.unwrap_or_default(),
+        label
+      );
+    }
+
+    if proxy.send_event(UserEvent::Devices(devices)).is_err() {
+      warn!("event loop closed before changed devices were handed over");
+    }
+  }
+
+  /// Wait for the battery source to come back, retrying with exponential
+  /// backoff, then hand the fresh device list over to the event loop
+  fn reconnect(
+    icon_self: &Mutex<TrayInner>,
+    source: &Source,
+    renderer: &Renderer,
+    proxy: &EventLoopProxy<UserEvent>,
+  ) {
+    let name = source.lock().unwrap().name();
+
+    warn!("disconnected from {}, waiting for it to come back", name);
+    // A cross is displayed while the battery source can't be reached
+    Self::show_status(
+      proxy,
+      Self::glyph_icon(renderer, Glyph::Disconnected),
+      format!("elem (disconnected from {name})"),
+    );
+
+    let mut backoff = crate::source::Backoff::default();
+
+    loop {
+      backoff.wait();
+
+      let devices = source.lock().unwrap().devices();
+
+      match devices {
+        Ok(devices) => {
+          info!("reconnected to {}", name);
+          Self::hand_over_devices(icon_self, devices, proxy);
+
+          return;
+        }
+        Err(e) => debug!("still disconnected from {}: {}", name, e),
+      }
+    }
+  }
+
+  /// Build the tray menu from a device list, checking whichever device is
+  /// selected
+  ///
+  /// The device, "Show Log Window", and "Quit" items are returned alongside
+  /// the menu so that the event loop can tell when they're clicked.
+  fn menu(
+    inner: &Mutex<TrayInner>,
+    devices: &HashMap<String, crate::source::DeviceInfo>,
+    log_window_state: bool,
+  ) -> (
+    menu::ContextMenu,
+    Vec<DeviceItem>,
+    CustomMenuItem,
+    CustomMenuItem,
+  ) {
     let mut tray_menu = menu::ContextMenu::new();
+    let mut device_items = vec![];
 
     tray_menu.add_item(
       menu::MenuItemAttributes::new(&format!(
         "Update frequency: {}ms",
-        local_self.lock().unwrap().update_frequency
+        inner.lock().unwrap().update_frequency
       ))
       .with_enabled(false),
     );
 
+    let mut devices = devices
+      .values()
+      .collect::<Vec<&crate::source::DeviceInfo>>();
+
+    devices.sort_by(|a, b| a.label.cmp(&b.label));
+
     // Adding all wireless devices to the tray icons devices menu
     tray_menu.add_submenu("Devices", true, {
       let mut menu = menu::ContextMenu::new();
-      let mut devices = devices
-        .values()
-        .collect::<Vec<&crate::logitech::DeviceInfo>>();
-
-      // Making sure that the last device, the default device, is never the
-      // dummy device
-      //
-      // There will always be a last device because the dummy device is always
-      // present.
-      if devices.last().unwrap().display_name == "Dummy (Debug)" {
-        // We can always pop the last device because there will always be a
-        // last element even if there is only one.
-        let last = devices.pop().unwrap();
-
-        devices.insert(0, last);
-      }
 
-      local_self.lock().unwrap().devices.clear();
+      for device_info in &devices {
+        // Menu item IDs are derived from the device ID instead of the label,
+        // so that renaming a device doesn't break its menu item.
+        let mut item = menu.add_item(
+          menu::MenuItemAttributes::new(&device_info.label)
+            .with_id(menu::MenuId::new(&device_info.id)),
+        );
 
-      for (i, device_info) in devices.iter().enumerate() {
-        let mut id = menu
-          .add_item(menu::MenuItemAttributes::new(&device_info.display_name));
+        if inner.lock().unwrap().selection.is_selected(&device_info.id) {
+          item.set_selected(true);
+        }
 
-        if i == devices.len() - 1 {
-          id.set_selected(true);
+        device_items.push(DeviceItem {
+          id: device_info.id.clone(),
+          label: device_info.label.clone(),
+          item,
+        });
+      }
 
-          local_self.lock().unwrap().selected_device_display_name =
-            Some(device_info.display_name.to_string());
-        }
+      menu
+    });
+    // Telling devices apart shouldn't take opening G HUB
+    tray_menu.add_submenu("Device Info", !devices.is_empty(), {
+      let mut menu = menu::ContextMenu::new();
 
-        local_self.lock().unwrap().devices.push(id);
+      for device_info in &devices {
+        menu.add_submenu(
+          &device_info.label,
+          true,
+          Self::info_menu(device_info),
+        );
       }
 
       menu
     });
 
-    let mut log_window =
-      tray_menu.add_item(menu::MenuItemAttributes::new("Show Log Window"));
-    let mut log_window_state = false;
+    // The log window item keeps the same ID no matter which title it's built
+    // with, so it can be found again after the menu is rebuilt.
+    let log_window = tray_menu.add_item(
+      menu::MenuItemAttributes::new(if log_window_state {
+        "Hide Log Window"
+      } else {
+        "Show Log Window"
+      })
+      .with_id(menu::MenuId::new("Show Log Window")),
+    );
     let quit = tray_menu.add_item(menu::MenuItemAttributes::new("Quit"));
-    let system_tray = Arc::new(Mutex::new(
-      system_tray::SystemTrayBuilder::new(
-        Self::icon(&local_self.lock().unwrap().selected_device_display_name),
-        Some(tray_menu),
+
+    (tray_menu, device_items, log_window, quit)
+  }
+
+  /// Build a menu listing everything the battery source knows about a device,
+  /// with every item disabled since they're only there to be read
+  fn info_menu(device_info: &crate::source::DeviceInfo) -> menu::ContextMenu {
+    let mut menu = menu::ContextMenu::new();
+    let mut add_info = |name: &str, value: &str| {
+      if !value.is_empty() {
+        menu.add_item(
+          menu::MenuItemAttributes::new(&format!("{name}: {value}"))
+            .with_enabled(false),
+        );
+      }
+    };
+
+    add_info("Name", &device_info.display_name);
+    add_info("ID", &device_info.id);
+    add_info("Type", device_info.device_type());
+    add_info("Connection", device_info.connection_type());
+
+    menu
+  }
+
+  /// Run the tray icon and event loop
+  #[allow(clippy::too_many_lines)]
+  pub fn run(&self) {
+    let local_self = self.inner.clone();
+    // Grab all devices, starting out disconnected if the battery source can't
+    // be reached
+    let devices = self.source.lock().unwrap().devices().unwrap_or_else(|e| {
+      warn!("{}", e);
+
+      HashMap::new()
+    });
+
+    local_self.lock().unwrap().selection.adopt(&devices);
+
+    // Set up the event loop and tray icon-related stuff
+    let event_loop = EventLoop::with_user_event();
+    let main_tray_id = tao::TrayId::new("main-tray");
+    let mut log_window_state = false;
+    let (tray_menu, mut devices, mut log_window, mut quit) =
+      Self::menu(&local_self, &devices, log_window_state);
+    let (watch_sender, watches) = std::sync::mpsc::channel();
+    // The watchman fetches the first battery level too, so that the tray
+    // shows up straight away even if the battery source takes its time
+    let (selected_device_id, label) =
+      local_self.lock().unwrap().selection.selected_device();
+    let (icon, tooltip) = if selected_device_id.is_some() {
+      let _ = watch_sender.send(Watch::Selected);
+
+      (
+        Self::glyph_icon(&self.renderer, Glyph::Loading),
+        format!("elem (updating {label})"),
       )
-      .with_id(main_tray_id)
-      .with_tooltip("elem")
-      .build(&event_loop)
-      .unwrap_or_else(|_| self::quit("failed to build system tray")),
-    ));
-    let mut devices = local_self.lock().unwrap().devices.clone();
+    } else {
+      (
+        Self::glyph_icon(&self.renderer, Glyph::Disconnected),
+        Self::no_device_tooltip(&self.source),
+      )
+    };
+    let mut system_tray =
+      system_tray::SystemTrayBuilder::new(icon, Some(tray_menu))
+        .with_id(main_tray_id)
+        .with_tooltip(&tooltip)
+        .build(&event_loop)
+        .unwrap_or_else(|_| self::quit("failed to build system tray"));
     let icon_self = self.inner.clone();
-    let system_tray_updater = system_tray.clone();
+    let icon_source = self.source.clone();
+    let icon_renderer = self.renderer.clone();
+    let renderer = self.renderer.clone();
+    let (push_sender, pushes) = std::sync::mpsc::channel();
+    let push_watch_sender = watch_sender.clone();
+    let proxy = event_loop.create_proxy();
+    // Cancels whatever the watchman is waiting on, so that a newly selected
+    // device doesn't have to wait for a slow battery source first
+    let canceller = self.source.lock().unwrap().canceller();
+
+    // The battery source listens for battery and device state changes in the
+    // background, if it can
+    self.source.lock().unwrap().subscribe(push_sender);
+
+    // Pushes are handed to the watchman alongside device selections, so that
+    // it only ever waits on one thing
+    std::thread::spawn(move || {
+      for push in pushes {
+        if push_watch_sender.send(Watch::Push(push)).is_err() {
+          break;
+        }
+      }
+    });
 
-    // An thread which updates the tray icon (battery level) every minute
+    // An thread which updates the tray icon (battery level) and devices menu
+    // whenever they change, or every minute if the battery source hasn't
+    // pushed anything
     std::thread::spawn(move || {
-      Self::watchman(&icon_self, &system_tray_updater);
+      Self::watchman(
+        &icon_self,
+        &icon_source,
+        &icon_renderer,
+        &watches,
+        &proxy,
+      );
     });
 
     // The event loop which takes care of switching devices, handling menu
@@ -304,20 +681,21 @@ impl Tray {
         } => {
           if menu_id == quit.clone().id() {
             info!("quitting");
+            canceller.cancel();
 
             *control_flow = ControlFlow::Exit;
           }
 
           if menu_id == log_window.clone().id() {
             if log_window_state {
-              unsafe { ShowWindow(GetConsoleWindow(), winuser::SW_HIDE) };
+              show_log_window(false);
 
               log_window.set_title("Show Log Window");
               trace!("hiding log window from intent");
 
               log_window_state = false;
             } else {
-              unsafe { ShowWindow(GetConsoleWindow(), winuser::SW_SHOW) };
+              show_log_window(true);
 
               log_window.set_title("Hide Log Window");
               trace!("showing log window from intent");
@@ -330,61 +708,67 @@ impl Tray {
           //
           // If a new device was selected, update the icon and update the menu
           // accordingly.
-          if devices.iter().any(|d| d.clone().id() == menu_id) {
+          if devices.iter().any(|d| d.item.clone().id() == menu_id) {
             for device in &mut devices {
-              if menu_id == device.clone().id() {
-                debug!("selected device '{}'", device.clone().title());
-                device.set_selected(true);
+              if menu_id == device.item.clone().id() {
+                debug!("selected device '{}' ({})", device.label, device.id);
+                device.item.set_selected(true);
                 // Ellipsis icon to indicate background process
                 system_tray
-                  .lock()
-                  .unwrap()
-                  .set_icon(Self::force_icon("80085"));
-                trace!("updating system tray icon from intent");
-
-                // If the selected device is the dummy device, set a dummy icon
-                if device.0.title() == "Dummy (Debug)" {
-                  system_tray
-                    .lock()
-                    .unwrap()
-                    .set_icon(Self::force_icon("43770"));
-                } else {
-                  system_tray
-                    .lock()
-                    .unwrap()
-                    .set_icon(Self::icon(&Some(device.0.title())));
-                }
-
-                trace!("updated system tray icon from intent");
-                system_tray.lock().unwrap().set_tooltip(&format!(
+                  .set_icon(Self::glyph_icon(&renderer, Glyph::Loading));
+                system_tray.set_tooltip(&format!(
                   "elem (updating {} from intent)",
-                  device.0.title()
+                  device.label
                 ));
-                local_self.lock().unwrap().selected_device_display_name =
-                  Some(device.0.title());
-                system_tray
-                  .lock()
-                  .unwrap()
-                  .set_tooltip(&format!("elem ({})", device.0.title()));
+                // Selecting before fetching, so that the watchman fetches the
+                // new device from now on
+                local_self.lock().unwrap().selection.select(&device.id);
+                // Whatever the watchman is fetching is stale now, and it would
+                // otherwise hold onto the battery source until it's done
+                canceller.cancel();
+
+                // The battery level is fetched by the watchman, so that the
+                // event loop never waits on the battery source
+                if watch_sender.send(Watch::Selected).is_err() {
+                  warn!("watchman stopped before selected device was fetched");
+                }
+
                 info!(
                   "completed device selection ({}) and associated tasks",
-                  device.0.title()
+                  device.label
                 );
               } else {
-                device.set_selected(false);
+                device.item.set_selected(false);
               }
             }
           }
         }
-        Event::TrayEvent { id, event, .. } => {
+        // The devices menu is rebuilt from the fresh device list, which the
+        // watchman has already selected from and fetches the battery level for
+        Event::UserEvent(UserEvent::Devices(fresh_devices)) => {
+          let (tray_menu, new_devices, new_log_window, new_quit) =
+            Self::menu(&local_self, &fresh_devices, log_window_state);
+
+          system_tray.set_menu(&tray_menu);
+
+          log_window = new_log_window;
+          quit = new_quit;
+          devices = new_devices;
+
+          info!("rebuilt devices menu");
+        }
+        Event::UserEvent(UserEvent::Status(icon, tooltip)) => {
+          system_tray.set_icon(icon);
+          system_tray.set_tooltip(&tooltip);
+        }
+        Event::TrayEvent { id, event, .. }
           if id == main_tray_id
             && event == tao::event::TrayEvent::LeftClick
-            && !log_window_state
-          {
-            unsafe { ShowWindow(GetConsoleWindow(), winuser::SW_SHOW) };
+            && !log_window_state =>
+        {
+          show_log_window(true);
 
-            trace!("showing log window from tray event");
-          }
+          trace!("showing log window from tray event");
         }
         _ => {}
       }
@@ -392,11 +776,39 @@ impl Tray {
   }
 }
 
+/// Show or hide the log window
+#[cfg(windows)]
+fn show_log_window(show: bool) {
+  unsafe {
+    ShowWindow(
+      GetConsoleWindow(),
+      if show {
+        winuser::SW_SHOW
+      } else {
+        winuser::SW_HIDE
+      },
+    )
+  };
+}
+
+/// There's no log window outside of Windows, the logs just go wherever elem
+/// was started from.
+#[cfg(not(windows))]
+const fn show_log_window(_show: bool) {}
+
 pub fn quit(message: &str) -> ! {
   message_box(message);
   panic!("{}", message);
 }
 
+#[cfg(not(windows))]
+pub fn message_box(message: &str) -> i32 {
+  error!("{}", message);
+
+  0
+}
+
+#[cfg(windows)]
 pub fn message_box(
   // title: &str,
   message: &str, // buttons: u32, icon: u32
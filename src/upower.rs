@@ -0,0 +1,447 @@
+// This file is part of elem <https://github.com/Fuwn/elem>.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, version 3.
+//
+// This program is distributed in the hope that it will be useful, but
+// WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU
+// General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+//
+// Copyright (C) 2022-2022 Fuwn <contact@fuwn.me>
+// SPDX-License-Identifier: GPL-3.0-only
+
+use std::{collections::HashMap, sync::mpsc::Sender};
+
+use zbus::{
+  blocking::{Connection, Proxy},
+  zvariant::{OwnedObjectPath, OwnedValue},
+  MatchRule, Message, MessageType,
+};
+
+use crate::{
+  config::Bus,
+  dbus::Service,
+  source::{BatteryState, BatteryStateChange, DeviceInfo, Push},
+};
+
+const SERVICE: &str = "org.freedesktop.UPower";
+const PATH: &str = "/org/freedesktop/UPower";
+const DEVICE_INTERFACE: &str = "org.freedesktop.UPower.Device";
+
+/// The `UPower` device properties which make up a battery state
+const BATTERY_PROPERTIES: [&str; 5] = [
+  "Percentage",
+  "State",
+  "BatteryLevel",
+  "WarningLevel",
+  "Voltage",
+];
+
+// https://upower.freedesktop.org/docs/Device.html
+const LINE_POWER: u32 = 1;
+const CHARGING: u32 = 1;
+const LOW: u32 = 3;
+const CRITICAL: u32 = 4;
+
+/// The G HUB-style name of a `UPower` device type
+const fn device_type(kind: u32) -> &'static str {
+  match kind {
+    5 => "MOUSE",
+    6 => "KEYBOARD",
+    8 => "PHONE",
+    10 => "TABLET",
+    12 => "GAMING_INPUT",
+    13 => "PEN",
+    14 => "TOUCHPAD",
+    17 => "HEADSET",
+    18 => "SPEAKERS",
+    19 => "HEADPHONES",
+    22 => "REMOTE_CONTROL",
+    _ => "UNKNOWN",
+  }
+}
+
+/// Peripheral batteries known to `UPower` (`org.freedesktop.UPower`), like
+/// wireless mice, keyboards, and Bluetooth headsets
+///
+/// Each device's object path is used as its device ID.
+pub struct UPower {
+  service: Service,
+}
+
+impl UPower {
+  pub const fn new(bus: Bus) -> Self {
+    Self {
+      service: Service::new("upower", bus),
+    }
+  }
+
+  fn device<'a>(
+    connection: &'a Connection,
+    path: &str,
+  ) -> zbus::Result<Proxy<'a>> {
+    Proxy::new(connection, SERVICE, path.to_string(), DEVICE_INTERFACE)
+  }
+
+  /// Whether a device is a peripheral, and not the system's own battery or a
+  /// charger
+  fn is_peripheral(device: &Proxy<'_>) -> zbus::Result<bool> {
+    Ok(
+      !device.get_property::<bool>("PowerSupply")?
+        && device.get_property::<u32>("Type")? != LINE_POWER,
+    )
+  }
+
+  /// Read a device's battery state from its properties
+  fn battery_state(device: &Proxy<'_>) -> zbus::Result<BatteryState> {
+    let battery_level = device.get_property::<u32>("BatteryLevel")?;
+    let warning_level = device.get_property::<u32>("WarningLevel")?;
+    let voltage = device.get_property::<f64>("Voltage")?;
+
+    // UPower's percentages are always between zero and one hundred, and
+    // voltages are never negative.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(BatteryState::new(
+      device.get_property::<f64>("Percentage")?.round() as u64,
+      device.get_property::<u32>("State")? == CHARGING,
+      (voltage > 0.0).then(|| (voltage * 1000.0).round() as u64),
+      battery_level == CRITICAL || warning_level == CRITICAL,
+      battery_level == LOW || warning_level == LOW,
+    ))
+  }
+
+  /// List every peripheral `UPower` knows about
+  fn list(connection: &Connection) -> zbus::Result<Vec<DeviceInfo>> {
+    let paths = Proxy::new(connection, SERVICE, PATH, SERVICE)?
+      .call::<_, _, Vec<OwnedObjectPath>>("EnumerateDevices", &())?;
+    let mut devices = vec![];
+
+    for path in paths {
+      // A device going away while it's being read shouldn't hide every other
+      // device
+      match Self::peripheral(connection, path.as_str()) {
+        Ok(Some(device_info)) => devices.push(device_info),
+        Ok(None) => {}
+        Err(e) => warn!("skipping unreadable upower device {}: {}", path, e),
+      }
+    }
+
+    Ok(devices)
+  }
+
+  /// Describe a device, or nothing if it isn't a peripheral
+  fn peripheral(
+    connection: &Connection,
+    path: &str,
+  ) -> zbus::Result<Option<DeviceInfo>> {
+    let device = Self::device(connection, path)?;
+
+    if !Self::is_peripheral(&device)? {
+      return Ok(None);
+    }
+
+    let model = device.get_property::<String>("Model")?;
+
+    Ok(Some(DeviceInfo::new(
+      path,
+      "WIRELESS",
+      device_type(device.get_property::<u32>("Type")?),
+      if model.is_empty() {
+        path.rsplit('/').next().unwrap_or_default()
+      } else {
+        &model
+      },
+    )))
+  }
+
+  /// Turn device additions, removals, and battery state changes into pushes
+  fn to_push(
+    connection: &Connection,
+    message: &Message,
+  ) -> zbus::Result<Option<Push>> {
+    match message.member().as_deref() {
+      Some("DeviceAdded" | "DeviceRemoved") => Ok(Some(Push::DeviceState)),
+      Some("PropertiesChanged") => {
+        let (interface, changed, _) =
+          message
+            .body::<(String, HashMap<String, OwnedValue>, Vec<String>)>()?;
+        let Some(path) = message.path() else {
+          return Ok(None);
+        };
+
+        // UPower updates things like timestamps all the time, which don't
+        // change anything elem shows.
+        if interface != DEVICE_INTERFACE
+          || !BATTERY_PROPERTIES
+            .iter()
+            .any(|property| changed.contains_key(*property))
+        {
+          return Ok(None);
+        }
+
+        match Self::device(connection, path.as_str())
+          .and_then(|device| Self::battery_state(&device))
+        {
+          Ok(state) => {
+            trace!("received battery state change for '{}'", path);
+
+            Ok(Some(Push::BatteryState(BatteryStateChange::new(
+              path.as_str(),
+              state,
+            ))))
+          }
+          Err(e) => {
+            debug!("skipping unreadable battery state change: {}", e);
+
+            Ok(None)
+          }
+        }
+      }
+      _ => Ok(None),
+    }
+  }
+}
+
+impl crate::source::BatterySource for UPower {
+  fn name(&self) -> &'static str { "upower" }
+
+  fn is_connected(&self) -> bool { self.service.is_connected() }
+
+  fn devices(&mut self) -> crate::source::Result<HashMap<String, DeviceInfo>> {
+    self.service.devices(Self::list)
+  }
+
+  fn battery(&mut self, id: &str) -> crate::source::Result<BatteryState> {
+    let device = Self::device(self.service.connection()?, id)?;
+
+    if !Self::is_peripheral(&device)? {
+      return Err(format!("'{id}' is not a peripheral").into());
+    }
+
+    Ok(Self::battery_state(&device)?)
+  }
+
+  fn subscribe(&mut self, sender: Sender<Push>) {
+    // This will never fail because `PATH` is a valid object path.
+    let rule = MatchRule::builder()
+      .msg_type(MessageType::Signal)
+      .path_namespace(PATH)
+      .unwrap()
+      .build();
+
+    self.service.subscribe(rule, Self::to_push, sender);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::mpsc;
+
+  use zbus::{dbus_interface, zvariant::ObjectPath, SignalContext};
+
+  use super::*;
+  use crate::{
+    dbus::tests::{push_from, PrivateBus},
+    source::BatterySource,
+  };
+
+  const MOUSE: &str = "/org/freedesktop/UPower/devices/mouse_hidpp_battery_0";
+  const LAPTOP: &str = "/org/freedesktop/UPower/devices/battery_BAT0";
+  const AC: &str = "/org/freedesktop/UPower/devices/line_power_AC";
+  /// A device `UPower` lists, but which is gone by the time it's read
+  const GONE: &str = "/org/freedesktop/UPower/devices/keyboard_hidpp_battery_1";
+
+  /// A stand-in for `UPower` itself, with every device it knows about
+  struct Daemon {
+    paths: Vec<&'static str>,
+  }
+
+  #[dbus_interface(name = "org.freedesktop.UPower")]
+  impl Daemon {
+    fn enumerate_devices(&self) -> Vec<OwnedObjectPath> {
+      self
+        .paths
+        .iter()
+        .map(|path| ObjectPath::try_from(*path).unwrap().into())
+        .collect()
+    }
+
+    #[dbus_interface(signal)]
+    async fn device_added(
+      context: &SignalContext<'_>,
+      device: ObjectPath<'_>,
+    ) -> zbus::Result<()>;
+  }
+
+  /// A stand-in for a device `UPower` knows about
+  struct Device {
+    kind: u32,
+    power_supply: bool,
+    model: &'static str,
+    percentage: f64,
+    state: u32,
+    battery_level: u32,
+    warning_level: u32,
+    voltage: f64,
+  }
+
+  impl Default for Device {
+    fn default() -> Self {
+      Self {
+        kind: 5,
+        power_supply: false,
+        model: "",
+        percentage: 100.0,
+        state: 2,
+        battery_level: 1,
+        warning_level: 1,
+        voltage: 0.0,
+      }
+    }
+  }
+
+  #[dbus_interface(name = "org.freedesktop.UPower.Device")]
+  impl Device {
+    #[dbus_interface(property, name = "Type")]
+    fn kind(&self) -> u32 { self.kind }
+
+    #[dbus_interface(property)]
+    fn power_supply(&self) -> bool { self.power_supply }
+
+    #[dbus_interface(property)]
+    fn model(&self) -> String { self.model.to_string() }
+
+    #[dbus_interface(property)]
+    fn percentage(&self) -> f64 { self.percentage }
+
+    #[dbus_interface(property)]
+    fn state(&self) -> u32 { self.state }
+
+    #[dbus_interface(property)]
+    fn battery_level(&self) -> u32 { self.battery_level }
+
+    #[dbus_interface(property)]
+    fn warning_level(&self) -> u32 { self.warning_level }
+
+    #[dbus_interface(property)]
+    fn voltage(&self) -> f64 { self.voltage }
+  }
+
+  /// Serve a mouse, the laptop's own battery, and its charger, listing a
+  /// keyboard which isn't served at all
+  fn serve(bus: &PrivateBus) -> Connection {
+    bus
+      .serve(SERVICE)
+      .serve_at(
+        PATH,
+        Daemon {
+          paths: vec![MOUSE, LAPTOP, GONE, AC],
+        },
+      )
+      .unwrap()
+      .serve_at(
+        MOUSE,
+        Device {
+          model: "G305",
+          percentage: 57.4,
+          state: CHARGING,
+          warning_level: LOW,
+          voltage: 3.9,
+          ..Device::default()
+        },
+      )
+      .unwrap()
+      .serve_at(
+        LAPTOP,
+        Device {
+          kind: 2,
+          power_supply: true,
+          ..Device::default()
+        },
+      )
+      .unwrap()
+      .serve_at(
+        AC,
+        Device {
+          kind: LINE_POWER,
+          ..Device::default()
+        },
+      )
+      .unwrap()
+      .build()
+      .unwrap()
+  }
+
+  #[test]
+  fn lists_only_peripherals() {
+    let bus = PrivateBus::start();
+    let _upower = serve(&bus);
+    let devices = UPower::new(bus.bus()).devices().unwrap();
+
+    assert_eq!(devices.keys().collect::<Vec<_>>(), [MOUSE]);
+    assert_eq!(devices[MOUSE].label, "G305");
+    assert_eq!(devices[MOUSE].device_type(), "MOUSE");
+  }
+
+  #[test]
+  fn reads_the_battery_state() {
+    let bus = PrivateBus::start();
+    let _upower = serve(&bus);
+    let mut upower = UPower::new(bus.bus());
+    let state = upower.battery(MOUSE).unwrap();
+
+    assert_eq!(state.percentage(), 57);
+    assert!(state.is_charging());
+    assert_eq!(state.millivolts(), Some(3900));
+    assert!(state.is_low() && !state.is_critical());
+    assert!(upower.battery(LAPTOP).is_err());
+  }
+
+  #[test]
+  fn pushes_battery_and_device_changes() {
+    let bus = PrivateBus::start();
+    let connection = serve(&bus);
+    let (sender, pushes) = mpsc::channel();
+    let mouse = connection
+      .object_server()
+      .interface::<_, Device>(MOUSE)
+      .unwrap();
+    let daemon = connection
+      .object_server()
+      .interface::<_, Daemon>(PATH)
+      .unwrap();
+
+    UPower::new(bus.bus()).subscribe(sender);
+    mouse.get_mut().percentage = 12.0;
+    push_from(
+      || {
+        zbus::block_on(mouse.get().percentage_changed(mouse.signal_context()))
+          .unwrap();
+      },
+      &pushes,
+      |push| {
+        matches!(
+          push,
+          Push::BatteryState(change)
+            if change.device_id() == MOUSE && change.state().percentage() == 12
+        )
+      },
+    );
+    push_from(
+      || {
+        zbus::block_on(Daemon::device_added(
+          daemon.signal_context(),
+          ObjectPath::try_from(MOUSE).unwrap(),
+        ))
+        .unwrap();
+      },
+      &pushes,
+      |push| matches!(push, Push::DeviceState),
+    );
+  }
+}